@@ -0,0 +1,405 @@
+use crate::config_schema::KeyType;
+use crate::global_config::CommandEnv;
+use crate::logging::log_warn;
+use crate::package_synchronizer::*;
+use crate::{get_from_table, AResult, CommandVector};
+
+use std::any::Any;
+use std::sync::Arc;
+
+/// All keys of a command config section, with their types.
+/// Used to check for unknown keys and to generate the config schema.
+pub const COMMAND_KEYS: [(&str, KeyType); 10] = [
+    ("type", KeyType::String),
+    ("name", KeyType::String),
+    ("packages", KeyType::StringList),
+    ("groups", KeyType::StringList),
+    ("group_expand_cmd", KeyType::Command),
+    ("installed_cmd", KeyType::Command),
+    ("installed_file", KeyType::String),
+    ("install_cmd", KeyType::Command),
+    ("remove_cmd", KeyType::Command),
+    ("update_cmd", KeyType::Command),
+];
+
+/// Synchronizer for any package manager that can list, install and remove packages by name,
+/// like `cargo install`, `npm -g` or `pipx`. All commands are given in the config:
+///
+/// ```toml
+/// [cargo]
+/// type = "command"
+/// packages = ["ripgrep", "fd-find"]
+/// installed_cmd = ["sh", "-c", "cargo install --list | grep -v '^ ' | cut -d' ' -f1"]
+/// install_cmd = "cargo install"
+/// remove_cmd = "cargo uninstall"
+/// ```
+///
+/// Instead of `installed_cmd`, `installed_file` can name a file with one installed package per line.
+/// `groups` are expanded into their packages by running `group_expand_cmd` with the group name appended.
+#[derive(Debug, Clone)]
+pub struct CommandSynchronizer {
+    name: String,
+    packages: Vec<String>,
+    groups: Vec<String>,
+    group_expand_cmd: Option<CommandVector>,
+    installed_cmd: Option<CommandVector>,
+    installed_file: Option<String>,
+    install_cmd: CommandVector,
+    remove_cmd: CommandVector,
+    update_cmd: Option<CommandVector>,
+    /// Allow removals even if no packages are configured. Set from the command line.
+    allow_empty_config: bool,
+    /// Allow groups without packages. Set from the command line.
+    allow_empty_groups: bool,
+    /// Never remove packages. Set from the command line.
+    no_remove: bool,
+    runner: Arc<dyn QueryRunner>,
+}
+
+pub fn new_command(config: &toml::Table, query_env: CommandEnv) -> AResult<CommandSynchronizer> {
+    let runner = SystemQueryRunner {
+        env: query_env,
+        ..Default::default()
+    };
+    new_command_with_runner(config, Arc::new(runner))
+}
+
+/// Reads an optional command of the config.
+fn get_cmd(config: &toml::Table, key: &str) -> AResult<Option<CommandVector>> {
+    match config.get(key) {
+        Some(val) => {
+            let cmd = toml_value_to_cmd_array(key, val)?;
+            validate_cmd_override(key, &cmd, None)?;
+            Ok(Some(cmd))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn new_command_with_runner(config: &toml::Table, runner: Arc<dyn QueryRunner>) -> AResult<CommandSynchronizer> {
+    // Check for unknown keys
+    for k in config.keys() {
+        if !COMMAND_KEYS.iter().any(|(key, _)| key == k) {
+            return Err(format!("Unknown key: {}", k).into());
+        }
+    }
+
+    let installed_cmd = get_cmd(config, "installed_cmd")?;
+    let installed_file: Option<String> = get_from_table(config, "installed_file", None)?;
+    if installed_cmd.is_some() == installed_file.is_some() {
+        return Err("Exactly one of installed_cmd and installed_file is needed".into());
+    }
+    let groups: Vec<String> = get_from_table(config, "groups", Vec::new())?;
+    let group_expand_cmd = get_cmd(config, "group_expand_cmd")?;
+    if !groups.is_empty() && group_expand_cmd.is_none() {
+        return Err("groups need group_expand_cmd".into());
+    }
+
+    let mut command_config = CommandSynchronizer {
+        name: get_from_table(config, "name", String::new())?,
+        packages: get_from_table(config, "packages", Vec::new())?,
+        groups,
+        group_expand_cmd,
+        installed_cmd,
+        installed_file,
+        install_cmd: get_cmd(config, "install_cmd")?.ok_or("install_cmd is missing")?,
+        remove_cmd: get_cmd(config, "remove_cmd")?.ok_or("remove_cmd is missing")?,
+        update_cmd: get_cmd(config, "update_cmd")?,
+        allow_empty_config: false,
+        allow_empty_groups: false,
+        no_remove: false,
+        runner,
+    };
+
+    // The list comparisons rely on sorted lists
+    cleanup_package_list(&mut command_config.packages);
+
+    Ok(command_config)
+}
+
+impl CommandSynchronizer {
+    pub fn set_allow_empty_config(&mut self, allow: bool) {
+        self.allow_empty_config = allow;
+    }
+
+    pub fn set_allow_empty_groups(&mut self, allow: bool) {
+        self.allow_empty_groups = allow;
+    }
+
+    /// The configured packages and the packages of the configured groups.
+    fn calculate_config_state(&self) -> AResult<Vec<String>> {
+        let mut packages = self.packages.clone();
+        if let Some(expand_cmd) = &self.group_expand_cmd {
+            let mut empty_groups = Vec::new();
+            for group in &self.groups {
+                let mut cmd = expand_cmd.clone();
+                cmd.push(group.clone());
+                let group_packages = self.runner.get_packages(&cmd)?;
+                if group_packages.is_empty() {
+                    empty_groups.push(group.clone());
+                }
+                packages.extend(group_packages);
+            }
+
+            // An empty group is most likely a typo in the group name
+            if !empty_groups.is_empty() {
+                if !self.allow_empty_groups {
+                    return Err(format!(
+                        "Groups without packages: {}. Check the group names or use --allow-empty-groups.",
+                        empty_groups.join(", ")
+                    )
+                    .into());
+                }
+                log_warn!("Groups without packages: {}", empty_groups.join(", "));
+            }
+        }
+        cleanup_package_list(&mut packages);
+        Ok(packages)
+    }
+
+    fn installed_packages(&self) -> AResult<Vec<String>> {
+        let mut packages = match (&self.installed_cmd, &self.installed_file) {
+            (Some(cmd), _) => self.runner.get_packages(cmd)?,
+            (None, Some(path)) => get_packages_from_file(path)?,
+            (None, None) => Vec::new(),
+        };
+        cleanup_package_list(&mut packages);
+        Ok(packages)
+    }
+
+    fn calculate_to_install(&self) -> AResult<Vec<String>> {
+        Ok(compare_lists_only_in_first(
+            &self.calculate_config_state()?,
+            &self.installed_packages()?,
+        ))
+    }
+
+    fn calculate_to_remove(&self) -> AResult<Vec<String>> {
+        self.calculate_to_remove_from(&self.calculate_config_state()?, &self.installed_packages()?)
+    }
+
+    /// Like `calculate_to_remove`, for an already calculated config state and already queried installed packages.
+    fn calculate_to_remove_from(&self, config_state: &[String], installed_packages: &[String]) -> AResult<Vec<String>> {
+        if self.no_remove {
+            return Ok(Vec::new());
+        }
+
+        let to_remove = compare_lists_only_in_first(installed_packages, config_state);
+        // An empty config is most likely a mistake and would remove every package
+        if config_state.is_empty() && !self.allow_empty_config && !to_remove.is_empty() {
+            return Err(format!(
+                "The config has no packages. This would remove all {} installed packages. \
+                 Use --allow-empty-config if this is intended.",
+                to_remove.len()
+            )
+            .into());
+        }
+        Ok(to_remove)
+    }
+
+    /// Appends the packages to the command, if there are any.
+    fn cmds_for(cmd: &CommandVector, packages: Vec<String>) -> Vec<CommandVector> {
+        if packages.is_empty() {
+            return Vec::new();
+        }
+        let mut cmd = cmd.clone();
+        cmd.extend(packages);
+        vec![cmd]
+    }
+}
+
+impl SystemConfigSynchronizer for CommandSynchronizer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_no_remove(&mut self, no_remove: bool) {
+        self.no_remove = no_remove;
+    }
+
+    fn get_pre_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(self.update_cmd.iter().cloned().collect())
+    }
+
+    fn get_post_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(Vec::new())
+    }
+
+    fn get_up_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(Self::cmds_for(&self.install_cmd, self.calculate_to_install()?))
+    }
+
+    fn get_down_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(Self::cmds_for(&self.remove_cmd, self.calculate_to_remove()?))
+    }
+
+    /// Expands the groups and lists the installed packages only once for all phases.
+    fn plan(&self, phases: &[&str]) -> AResult<Plan> {
+        let runs = |phase: &str| phases.contains(&phase);
+        let (config_state, installed_packages) = if runs("Up") || runs("Down") {
+            (self.calculate_config_state()?, self.installed_packages()?)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        let mut plan = Plan::default();
+        if runs("Pre") {
+            plan.pre = self.get_pre_cmds()?;
+        }
+        if runs("Up") {
+            let to_install = compare_lists_only_in_first(&config_state, &installed_packages);
+            plan.up_changes = to_install.len();
+            plan.up = Self::cmds_for(&self.install_cmd, to_install);
+        }
+        if runs("Down") {
+            let to_remove = self.calculate_to_remove_from(&config_state, &installed_packages)?;
+            plan.down_changes = to_remove.len();
+            plan.down = Self::cmds_for(&self.remove_cmd, to_remove);
+        }
+        if runs("Post") {
+            plan.post = self.get_post_cmds()?;
+        }
+        Ok(plan)
+    }
+
+    fn count_up_changes(&self) -> AResult<usize> {
+        Ok(self.calculate_to_install()?.len())
+    }
+
+    fn count_down_changes(&self) -> AResult<usize> {
+        Ok(self.calculate_to_remove()?.len())
+    }
+
+    fn get_diff(&self) -> AResult<SyncDiff> {
+        Ok(SyncDiff {
+            to_install: self.calculate_to_install()?,
+            to_remove: self.calculate_to_remove()?,
+            ..Default::default()
+        })
+    }
+
+    fn get_planned_changes(&self) -> AResult<Vec<(String, Vec<String>)>> {
+        let diff = self.get_diff()?;
+        Ok(vec![
+            ("Install".to_string(), diff.to_install),
+            ("Remove".to_string(), diff.to_remove),
+        ])
+    }
+
+    fn get_explanation(&self) -> AResult<Vec<(String, Vec<String>)>> {
+        let source = match (&self.installed_cmd, &self.installed_file) {
+            (Some(cmd), _) => format!("Query: {}", cmd.join(" ")),
+            (None, Some(path)) => format!("File: {}", path),
+            (None, None) => "Installed".to_string(),
+        };
+        let mut sections = vec![
+            ("Config state".to_string(), self.calculate_config_state()?),
+            (source, self.installed_packages()?),
+        ];
+        sections.extend(self.get_planned_changes()?);
+        Ok(sections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::self_test::mock_runner;
+    use crate::test_util::{cmd, section, str_array, CountingQueryRunner};
+
+    use std::sync::atomic::Ordering;
+
+    use toml::Value;
+
+    #[test]
+    fn phases() {
+        let config = section(
+            "command",
+            &[
+                ("packages", str_array(&["black", "ruff"])),
+                ("installed_cmd", Value::String("pipx-list".to_string())),
+                ("install_cmd", Value::String("pipx install".to_string())),
+                ("remove_cmd", str_array(&["pipx", "uninstall"])),
+            ],
+        );
+        let s = new_command_with_runner(&config, Arc::new(mock_runner())).unwrap();
+        assert_eq!(s.get_up_cmds().unwrap(), vec![cmd(&["pipx", "install", "ruff"])]);
+        assert_eq!(s.get_down_cmds().unwrap(), vec![cmd(&["pipx", "uninstall", "httpie"])]);
+    }
+
+    #[test]
+    fn no_remove() {
+        let config = section(
+            "command",
+            &[
+                ("packages", str_array(&["ruff"])),
+                ("installed_cmd", Value::String("pipx-list".to_string())),
+                ("install_cmd", Value::String("pipx install".to_string())),
+                ("remove_cmd", str_array(&["pipx", "uninstall"])),
+            ],
+        );
+        let mut s = new_command_with_runner(&config, Arc::new(mock_runner())).unwrap();
+        s.set_no_remove(true);
+        let plan = s.plan(&["Pre", "Up", "Down", "Post"]).unwrap();
+        assert_eq!(plan.up, vec![cmd(&["pipx", "install", "ruff"])]);
+        assert!(plan.down.is_empty());
+        assert!(plan.post.is_empty());
+        assert!(s.get_down_cmds().unwrap().is_empty());
+    }
+
+    #[test]
+    fn groups() {
+        let runner = mock_runner()
+            .respond("pipx-group linters", &["ruff", "black"])
+            .respond("pipx-group typo", &[]);
+        let mut entries = vec![
+            ("groups", str_array(&["linters"])),
+            ("group_expand_cmd", Value::String("pipx-group".to_string())),
+            ("installed_cmd", Value::String("pipx-list".to_string())),
+            ("install_cmd", Value::String("pipx install".to_string())),
+            ("remove_cmd", str_array(&["pipx", "uninstall"])),
+        ];
+        let s = new_command_with_runner(&section("command", &entries), Arc::new(runner.clone())).unwrap();
+        assert_eq!(s.get_up_cmds().unwrap(), vec![cmd(&["pipx", "install", "ruff"])]);
+        assert_eq!(s.get_down_cmds().unwrap(), vec![cmd(&["pipx", "uninstall", "httpie"])]);
+
+        entries[0] = ("groups", str_array(&["linters", "typo"]));
+        let mut s = new_command_with_runner(&section("command", &entries), Arc::new(runner)).unwrap();
+        assert!(s.get_up_cmds().is_err());
+        s.set_allow_empty_groups(true);
+        assert_eq!(s.get_up_cmds().unwrap(), vec![cmd(&["pipx", "install", "ruff"])]);
+
+        entries.remove(1);
+        assert!(new_command_with_runner(&section("command", &entries), Arc::new(mock_runner())).is_err());
+    }
+
+    #[test]
+    fn plan_queries_once() {
+        let config = section(
+            "command",
+            &[
+                ("packages", str_array(&["black"])),
+                ("groups", str_array(&["linters"])),
+                ("group_expand_cmd", Value::String("pipx-group".to_string())),
+                ("installed_cmd", Value::String("pipx-list".to_string())),
+                ("install_cmd", Value::String("pipx install".to_string())),
+                ("remove_cmd", str_array(&["pipx", "uninstall"])),
+            ],
+        );
+        let runner = Arc::new(CountingQueryRunner::default());
+        let s = new_command_with_runner(&config, runner.clone()).unwrap();
+        let plan = s.plan(&["Pre", "Up", "Down", "Post"]).unwrap();
+        // One group expansion and one list of the installed packages
+        assert_eq!(runner.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(plan.up, s.get_up_cmds().unwrap());
+        assert_eq!(plan.down, s.get_down_cmds().unwrap());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::command_synchronizer::COMMAND_KEYS;
+use crate::flatpak::FLATPAK_KEYS;
+use crate::global_config::GLOBAL_KEYS;
+use crate::package_synchronizer::{aur_keys, PACMAN_KEYS};
+use crate::FALLBACK_KEYS;
+
+/// Type of a config key, as far as it is relevant for validation in an editor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    String,
+    Bool,
+    Integer,
+    StringList,
+    IntegerList,
+    /// Table with String values.
+    StringMap,
+    /// Command given as a String (split at whitespace) or as an Array of Strings.
+    Command,
+    /// Array of package names or `{ name = "...", note = "..." }` tables.
+    PackageList,
+    /// Integer number of bytes or a percentage String like `"10%"`.
+    FreeSpace,
+    /// Array of synchronizer sections, of which the first available one is used.
+    Backends,
+}
+
+impl KeyType {
+    fn json_schema(&self) -> String {
+        match self {
+            KeyType::String => r#"{ "type": "string" }"#.to_string(),
+            KeyType::Bool => r#"{ "type": "boolean" }"#.to_string(),
+            KeyType::Integer => r#"{ "type": "integer", "minimum": 0 }"#.to_string(),
+            KeyType::StringList => r#"{ "type": "array", "items": { "type": "string" } }"#.to_string(),
+            KeyType::IntegerList => r#"{ "type": "array", "items": { "type": "integer" } }"#.to_string(),
+            KeyType::StringMap => r#"{ "type": "object", "additionalProperties": { "type": "string" } }"#.to_string(),
+            KeyType::Command => {
+                r#"{ "oneOf": [{ "type": "string" }, { "type": "array", "items": { "type": "string" } }] }"#.to_string()
+            }
+            KeyType::PackageList => concat!(
+                r#"{ "type": "array", "items": { "oneOf": [{ "type": "string" }, "#,
+                r#"{ "type": "object", "properties": { "name": { "type": "string" }, "note": { "type": "string" } }, "#,
+                r#""required": ["name"], "additionalProperties": false }] } }"#
+            )
+            .to_string(),
+            KeyType::FreeSpace => {
+                r#"{ "oneOf": [{ "type": "integer", "minimum": 0 }, { "type": "string", "pattern": "^\\s*[0-9]+\\s*%$" }] }"#
+                    .to_string()
+            }
+            KeyType::Backends => r##"{ "type": "array", "items": { "$ref": "#/$defs/synchronizer" } }"##.to_string(),
+        }
+    }
+}
+
+/// One `"key": schema` line for each key.
+fn property_lines(keys: &[(&str, KeyType)], indent: &str) -> Vec<String> {
+    keys.iter()
+        .map(|(key, key_type)| format!("{}\"{}\": {}", indent, key, key_type.json_schema()))
+        .collect()
+}
+
+/// Schema of a synchronizer section. The `type` key selects the accepted keys.
+fn synchronizer_schema(type_name: &str, keys: &[(&str, KeyType)]) -> String {
+    let keys: Vec<(&str, KeyType)> = keys.iter().filter(|(k, _)| *k != "type").copied().collect();
+    let mut props = vec![format!("            \"type\": {{ \"const\": \"{}\" }}", type_name)];
+    props.extend(property_lines(&keys, "            "));
+    format!(
+        "{{\n          \"type\": \"object\",\n          \"required\": [\"type\"],\n          \"additionalProperties\": false,\n          \"properties\": {{\n{}\n          }}\n        }}",
+        props.join(",\n")
+    )
+}
+
+/// JSON Schema of the config file format, for validation and autocompletion in editors.
+/// Generated from the same key lists that are used to validate the config.
+pub fn config_json_schema() -> String {
+    let synchronizers = [
+        synchronizer_schema("pacman", &PACMAN_KEYS),
+        synchronizer_schema("aur", &aur_keys()),
+        synchronizer_schema("flatpak", &FLATPAK_KEYS),
+        synchronizer_schema("command", &COMMAND_KEYS),
+        synchronizer_schema("fallback", &FALLBACK_KEYS),
+    ];
+
+    // Global keys may also be set at the top level, except tables, which are read as sections there
+    let top_level_keys: Vec<(&str, KeyType)> = GLOBAL_KEYS
+        .iter()
+        .filter(|(_, t)| *t != KeyType::StringMap)
+        .copied()
+        .collect();
+
+    format!(
+        r##"{{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "title": "System Config Synchronizer config",
+  "type": "object",
+  "properties": {{
+    "include": {{ "type": "array", "items": {{ "type": "string" }} }},
+{},
+    "global": {{
+      "type": "object",
+      "additionalProperties": false,
+      "properties": {{
+{}
+      }}
+    }}
+  }},
+  "additionalProperties": {{ "$ref": "#/$defs/section" }},
+  "$defs": {{
+    "section": {{
+      "oneOf": [{{ "$ref": "#/$defs/synchronizer" }}, {{ "$ref": "#/$defs/nested" }}]
+    }},
+    "nested": {{
+      "type": "object",
+      "not": {{ "required": ["type"] }},
+      "additionalProperties": {{ "$ref": "#/$defs/section" }}
+    }},
+    "synchronizer": {{
+      "oneOf": [
+        {}
+      ]
+    }}
+  }}
+}}
+"##,
+        property_lines(&top_level_keys, "    ").join(",\n"),
+        property_lines(&GLOBAL_KEYS, "        ").join(",\n"),
+        synchronizers.join(",\n        ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_keys_typed_at_top_level() {
+        let schema = config_json_schema();
+        assert!(schema.contains("\n    \"dry_mode\": { \"type\": \"boolean\" },\n"));
+        assert!(schema.contains("\n        \"env\": { \"type\": \"object\""));
+        assert!(!schema.contains("\n    \"env\":"));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::config_schema::KeyType;
+use crate::global_config::CommandEnv;
+use crate::package_synchronizer::*;
+use crate::{get_from_table, AResult, CommandVector};
+
+use std::any::Any;
+use std::sync::Arc;
+
+/// All keys of a flatpak config section, with their types.
+/// Used to check for unknown keys and to generate the config schema.
+pub const FLATPAK_KEYS: [(&str, KeyType); 5] = [
+    ("type", KeyType::String),
+    ("name", KeyType::String),
+    ("allow_multiple", KeyType::Bool),
+    ("apps", KeyType::StringList),
+    ("remote", KeyType::String),
+];
+
+/// Synchronizes the installed Flatpak apps with a list of app IDs.
+/// Runtimes are not managed directly, they are installed with the apps and removed by the unused cleanup.
+#[derive(Debug, Clone)]
+pub struct FlatpakSynchronizer {
+    name: String,
+    /// IDs of the apps, like `org.mozilla.firefox`.
+    apps: Vec<String>,
+    /// Allow removals even if no apps are configured. Set from the command line.
+    allow_empty_config: bool,
+    /// Never uninstall apps or runtimes. Set from the command line.
+    no_remove: bool,
+    installed_apps_cmd: CommandVector,
+    /// Installs from the configured remote.
+    install_cmd: CommandVector,
+    uninstall_cmd: CommandVector,
+    update_cmd: CommandVector,
+    uninstall_unused_cmd: CommandVector,
+    runner: Arc<dyn QueryRunner>,
+}
+
+pub fn new_flatpak(config: &toml::Table, query_env: CommandEnv) -> AResult<FlatpakSynchronizer> {
+    let runner = SystemQueryRunner {
+        env: query_env,
+        ..Default::default()
+    };
+    new_flatpak_with_runner(config, Arc::new(runner))
+}
+
+pub fn new_flatpak_with_runner(config: &toml::Table, runner: Arc<dyn QueryRunner>) -> AResult<FlatpakSynchronizer> {
+    // Check for unknown keys
+    for k in config.keys() {
+        if !FLATPAK_KEYS.iter().any(|(key, _)| key == k) {
+            return Err(format!("Unknown key: {}", k).into());
+        }
+    }
+
+    let remote: String = get_from_table(config, "remote", "flathub".to_string())?;
+    let mut flatpak_config = FlatpakSynchronizer {
+        name: get_from_table(config, "name", String::new())?,
+        apps: get_from_table(config, "apps", Vec::new())?,
+        allow_empty_config: false,
+        no_remove: false,
+        installed_apps_cmd: vec![
+            "flatpak".to_string(),
+            "list".to_string(),
+            "--app".to_string(),
+            "--columns=application".to_string(),
+        ],
+        install_cmd: vec!["flatpak".to_string(), "install".to_string(), "-y".to_string(), remote],
+        uninstall_cmd: vec!["flatpak".to_string(), "uninstall".to_string()],
+        update_cmd: vec!["flatpak".to_string(), "update".to_string()],
+        uninstall_unused_cmd: vec!["flatpak".to_string(), "uninstall".to_string(), "--unused".to_string()],
+        runner,
+    };
+
+    // The list comparisons rely on sorted lists
+    cleanup_package_list(&mut flatpak_config.apps);
+
+    Ok(flatpak_config)
+}
+
+impl FlatpakSynchronizer {
+    pub fn set_allow_empty_config(&mut self, allow: bool) {
+        self.allow_empty_config = allow;
+    }
+
+    fn installed_apps(&self) -> AResult<Vec<String>> {
+        let mut apps = self.runner.get_packages(&self.installed_apps_cmd)?;
+        cleanup_package_list(&mut apps);
+        Ok(apps)
+    }
+
+    fn calculate_to_install(&self) -> AResult<Vec<String>> {
+        Ok(compare_lists_only_in_first(&self.apps, &self.installed_apps()?))
+    }
+
+    fn calculate_to_uninstall(&self) -> AResult<Vec<String>> {
+        self.calculate_to_uninstall_from(&self.installed_apps()?)
+    }
+
+    /// Like `calculate_to_uninstall`, for already queried installed apps.
+    fn calculate_to_uninstall_from(&self, installed_apps: &[String]) -> AResult<Vec<String>> {
+        if self.no_remove {
+            return Ok(Vec::new());
+        }
+
+        let to_uninstall = compare_lists_only_in_first(installed_apps, &self.apps);
+        // An empty config is most likely a mistake and would uninstall every app
+        if self.apps.is_empty() && !self.allow_empty_config && !to_uninstall.is_empty() {
+            return Err(format!(
+                "The config has no apps. This would uninstall all {} installed apps. \
+                 Use --allow-empty-config if this is intended.",
+                to_uninstall.len()
+            )
+            .into());
+        }
+        Ok(to_uninstall)
+    }
+
+    /// Appends the apps to the command, if there are any.
+    fn cmds_for(cmd: &CommandVector, apps: Vec<String>) -> Vec<CommandVector> {
+        if apps.is_empty() {
+            return Vec::new();
+        }
+        let mut cmd = cmd.clone();
+        cmd.extend(apps);
+        vec![cmd]
+    }
+}
+
+impl SystemConfigSynchronizer for FlatpakSynchronizer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_no_remove(&mut self, no_remove: bool) {
+        self.no_remove = no_remove;
+    }
+
+    fn get_pre_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(vec![self.update_cmd.clone()])
+    }
+
+    fn get_post_cmds(&self) -> AResult<Vec<CommandVector>> {
+        if self.no_remove {
+            // The cleanup uninstalls the unused runtimes
+            return Ok(Vec::new());
+        }
+        Ok(vec![self.uninstall_unused_cmd.clone()])
+    }
+
+    fn get_up_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(Self::cmds_for(&self.install_cmd, self.calculate_to_install()?))
+    }
+
+    fn get_down_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(Self::cmds_for(&self.uninstall_cmd, self.calculate_to_uninstall()?))
+    }
+
+    /// Lists the installed apps only once for all phases.
+    fn plan(&self, phases: &[&str]) -> AResult<Plan> {
+        let runs = |phase: &str| phases.contains(&phase);
+        let installed_apps = if runs("Up") || runs("Down") {
+            self.installed_apps()?
+        } else {
+            Vec::new()
+        };
+
+        let mut plan = Plan::default();
+        if runs("Pre") {
+            plan.pre = self.get_pre_cmds()?;
+        }
+        if runs("Up") {
+            let to_install = compare_lists_only_in_first(&self.apps, &installed_apps);
+            plan.up_changes = to_install.len();
+            plan.up = Self::cmds_for(&self.install_cmd, to_install);
+        }
+        if runs("Down") {
+            let to_uninstall = self.calculate_to_uninstall_from(&installed_apps)?;
+            plan.down_changes = to_uninstall.len();
+            plan.down = Self::cmds_for(&self.uninstall_cmd, to_uninstall);
+        }
+        if runs("Post") {
+            plan.post = self.get_post_cmds()?;
+        }
+        Ok(plan)
+    }
+
+    fn count_up_changes(&self) -> AResult<usize> {
+        Ok(self.calculate_to_install()?.len())
+    }
+
+    fn count_down_changes(&self) -> AResult<usize> {
+        Ok(self.calculate_to_uninstall()?.len())
+    }
+
+    fn get_diff(&self) -> AResult<SyncDiff> {
+        Ok(SyncDiff {
+            to_install: self.calculate_to_install()?,
+            to_remove: self.calculate_to_uninstall()?,
+            ..Default::default()
+        })
+    }
+
+    fn get_planned_changes(&self) -> AResult<Vec<(String, Vec<String>)>> {
+        let diff = self.get_diff()?;
+        Ok(vec![
+            ("Install".to_string(), diff.to_install),
+            ("Remove".to_string(), diff.to_remove),
+        ])
+    }
+
+    fn get_explanation(&self) -> AResult<Vec<(String, Vec<String>)>> {
+        let mut sections = vec![
+            ("Config state".to_string(), self.apps.clone()),
+            (
+                format!("Query: {}", self.installed_apps_cmd.join(" ")),
+                self.installed_apps()?,
+            ),
+        ];
+        sections.extend(self.get_planned_changes()?);
+        Ok(sections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::self_test::mock_runner;
+    use crate::test_util::{cmd, section, str_array, CountingQueryRunner};
+
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn phases() {
+        let config = section("flatpak", &[("apps", str_array(&["org.new", "org.a"]))]);
+        let s = new_flatpak_with_runner(&config, Arc::new(mock_runner())).unwrap();
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["flatpak", "install", "-y", "flathub", "org.new"])]
+        );
+        assert_eq!(
+            s.get_down_cmds().unwrap(),
+            vec![cmd(&["flatpak", "uninstall", "org.old"])]
+        );
+    }
+
+    #[test]
+    fn no_remove() {
+        let config = section("flatpak", &[("apps", str_array(&["org.new"]))]);
+        let mut s = new_flatpak_with_runner(&config, Arc::new(mock_runner())).unwrap();
+        s.set_no_remove(true);
+        let plan = s.plan(&["Pre", "Up", "Down", "Post"]).unwrap();
+        assert_eq!(plan.up, vec![cmd(&["flatpak", "install", "-y", "flathub", "org.new"])]);
+        assert!(plan.down.is_empty());
+        assert!(plan.post.is_empty());
+        assert!(s.get_down_cmds().unwrap().is_empty());
+        assert!(s.get_post_cmds().unwrap().is_empty());
+    }
+
+    #[test]
+    fn plan_lists_installed_apps_once() {
+        let config = section("flatpak", &[("apps", str_array(&["org.new"]))]);
+        let runner = Arc::new(CountingQueryRunner::default());
+        let s = new_flatpak_with_runner(&config, runner.clone()).unwrap();
+        let plan = s.plan(&["Pre", "Up", "Down", "Post"]).unwrap();
+        assert_eq!(runner.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(plan.up, s.get_up_cmds().unwrap());
+        assert_eq!(plan.down, s.get_down_cmds().unwrap());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::config_schema::KeyType;
+use crate::logging::{parse_log_level, LogLevel};
+use crate::{get_from_table, AResult};
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Settings that apply to the whole run instead of a single synchronizer.
+/// Read from the `[global]` table and from the top level keys of the config files, which are not tables.
+/// So `env` can only be set in `[global]`. A key that is set in both places is an error, neither one wins.
+#[derive(Debug, Clone)]
+pub struct GlobalConfig {
+    /// Only print the planned commands. If disabled, the commands are run after the preview.
+    pub dry_mode: bool,
+    /// Print a warning if the total number of changed packages exceeds this number.
+    /// Applying such a plan has to be confirmed, unless `--yes` is given.
+    pub change_warn_threshold: Option<usize>,
+    /// Abort if the total number of changed packages exceeds this number.
+    pub change_hard_limit: Option<usize>,
+    /// Abort if the filesystem at `free_space_path` has less free space than this.
+    pub min_free_space: Option<FreeSpace>,
+    /// Path on the filesystem that is checked for `min_free_space`.
+    /// Defaults to the pacman package cache.
+    pub free_space_path: Option<String>,
+    /// Default for `manage_install_reason` of all synchronizers that do not set it themselves.
+    pub manage_install_reason: Option<bool>,
+    /// Maximum number of query commands that run at the same time.
+    /// Defaults to the number of CPUs. Lower it on small devices.
+    pub query_jobs: Option<usize>,
+    /// Kill query commands that run longer than this many seconds, e.g. when waiting on a stale lock.
+    /// The commands that modify the system are never killed.
+    pub command_timeout: Option<u64>,
+    /// Environment variables for all spawned commands.
+    pub env: BTreeMap<String, String>,
+    /// Start spawned commands with an empty environment, except for `env`.
+    pub clear_env: bool,
+    /// Level of the diagnostics on stderr. Overridden by `--log-level` and `RUST_LOG`.
+    pub log_level: Option<LogLevel>,
+    /// Lock file that prevents two runs from changing the system at the same time.
+    pub lock_file: Option<String>,
+    /// Append the plan of each run to this file. Dry runs are only recorded with `--journal-dry`.
+    pub journal_file: Option<String>,
+    /// Unique id of this run, to correlate the different outputs of a run. Not read from the config.
+    pub run_id: String,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        GlobalConfig {
+            dry_mode: true,
+            change_warn_threshold: None,
+            change_hard_limit: None,
+            min_free_space: None,
+            free_space_path: None,
+            manage_install_reason: None,
+            query_jobs: None,
+            command_timeout: None,
+            env: BTreeMap::new(),
+            clear_env: false,
+            log_level: None,
+            lock_file: None,
+            journal_file: None,
+            run_id: String::new(),
+        }
+    }
+}
+
+/// Generates a run id from the current time and a short random part.
+pub fn generate_run_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    // RandomState is randomly seeded, which is good enough for a short random suffix
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(now.as_nanos());
+    hasher.write_u32(std::process::id());
+    format!("{}-{:04x}", now.as_secs(), hasher.finish() & 0xffff)
+}
+
+/// Environment of spawned commands.
+#[derive(Debug, Clone, Default)]
+pub struct CommandEnv {
+    /// Start with an empty environment instead of inheriting it.
+    pub clear: bool,
+    pub vars: BTreeMap<String, String>,
+    /// Kill the command if it does not finish in time.
+    pub timeout: Option<Duration>,
+}
+
+impl CommandEnv {
+    pub fn apply(&self, cmd: &mut Command) {
+        if self.clear {
+            cmd.env_clear();
+        }
+        cmd.envs(&self.vars);
+    }
+}
+
+pub const DEFAULT_FREE_SPACE_PATH: &str = "/var/cache/pacman/pkg";
+
+/// Shared by all users, so that a run as root and a run with sudo exclude each other.
+pub const DEFAULT_LOCK_FILE: &str = "/run/lock/system-config-synchronizer.lock";
+
+/// Amount of free space, either absolute or relative to the size of the filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeSpace {
+    Bytes(u64),
+    Percent(u64),
+}
+
+/// Parses `min_free_space`, which is either an integer number of bytes or a string like `"10%"`.
+fn toml_value_to_free_space(val: Option<&toml::Value>) -> AResult<Option<FreeSpace>> {
+    match val {
+        None => Ok(None),
+        Some(toml::Value::Integer(i)) if *i >= 0 => Ok(Some(FreeSpace::Bytes(*i as u64))),
+        Some(toml::Value::String(s)) => match s.strip_suffix('%').map(|p| p.trim().parse::<u64>()) {
+            Some(Ok(p)) if p <= 100 => Ok(Some(FreeSpace::Percent(p))),
+            _ => Err(format!("min_free_space is not a valid percentage: {}", s).into()),
+        },
+        Some(_) => Err("min_free_space is not a positive Integer or a percentage String!".into()),
+    }
+}
+
+impl GlobalConfig {
+    /// Fills in the global defaults for all keys that a synchronizer config does not set itself.
+    pub fn apply_defaults(&self, config: &toml::Table) -> toml::Table {
+        let mut config = config.clone();
+        if let Some(m) = self.manage_install_reason {
+            config
+                .entry("manage_install_reason".to_string())
+                .or_insert(toml::Value::Boolean(m));
+        }
+        config
+    }
+
+    /// Environment of commands that modify the system.
+    pub fn command_env(&self) -> CommandEnv {
+        CommandEnv {
+            clear: self.clear_env,
+            vars: self.env.clone(),
+            timeout: None,
+        }
+    }
+
+    /// Environment of query commands. Forces `LC_ALL=C` unless overridden,
+    /// so that the output is byte-order sorted instead of locale sorted.
+    pub fn query_env(&self) -> CommandEnv {
+        let mut env = self.command_env();
+        env.vars.entry("LC_ALL".to_string()).or_insert("C".to_string());
+        env.timeout = self.command_timeout.map(Duration::from_secs);
+        env
+    }
+}
+
+/// All keys of the global config, with their types.
+/// Used to check for unknown keys and to generate the config schema.
+pub const GLOBAL_KEYS: [(&str, KeyType); 13] = [
+    ("dry_mode", KeyType::Bool),
+    ("change_warn_threshold", KeyType::Integer),
+    ("change_hard_limit", KeyType::Integer),
+    ("min_free_space", KeyType::FreeSpace),
+    ("free_space_path", KeyType::String),
+    ("manage_install_reason", KeyType::Bool),
+    ("query_jobs", KeyType::Integer),
+    ("command_timeout", KeyType::Integer),
+    ("env", KeyType::StringMap),
+    ("clear_env", KeyType::Bool),
+    ("journal_file", KeyType::String),
+    ("log_level", KeyType::String),
+    ("lock_file", KeyType::String),
+];
+
+pub fn new_global_config(config: &toml::Table) -> AResult<GlobalConfig> {
+    // Check for unknown keys
+    for k in config.keys() {
+        if !GLOBAL_KEYS.iter().any(|(key, _)| key == k) {
+            return Err(format!("Unknown key in global configuration: {}", k).into());
+        }
+    }
+
+    let global_config = GlobalConfig {
+        dry_mode: get_from_table(config, "dry_mode", true)?,
+        change_warn_threshold: get_from_table(config, "change_warn_threshold", None)?,
+        change_hard_limit: get_from_table(config, "change_hard_limit", None)?,
+        min_free_space: toml_value_to_free_space(config.get("min_free_space"))?,
+        free_space_path: get_from_table(config, "free_space_path", None)?,
+        manage_install_reason: get_from_table(config, "manage_install_reason", None)?,
+        query_jobs: get_from_table(config, "query_jobs", None)?,
+        command_timeout: get_from_table(config, "command_timeout", None)?,
+        env: get_from_table(config, "env", BTreeMap::new())?,
+        clear_env: get_from_table(config, "clear_env", false)?,
+        journal_file: get_from_table(config, "journal_file", None)?,
+        lock_file: get_from_table(config, "lock_file", None)?,
+        log_level: get_from_table::<Option<String>>(config, "log_level", None)?
+            .map(|l| parse_log_level(&l))
+            .transpose()?,
+        run_id: String::new(),
+    };
+
+    if global_config.query_jobs == Some(0) {
+        return Err("query_jobs must be at least 1".into());
+    }
+    if global_config.command_timeout == Some(0) {
+        return Err("command_timeout must be at least 1".into());
+    }
+
+    Ok(global_config)
+}
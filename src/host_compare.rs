@@ -0,0 +1,108 @@
+use crate::package_synchronizer::{cleanup_package_list, compare_lists_only_in_first};
+use crate::AResult;
+
+use std::fs;
+use std::path::Path;
+
+/// Parses an exported package list: a JSON array of package names.
+/// For example the output of `pacman -Qqe | jq -R . | jq -s .`.
+pub fn parse_package_list_json(content: &str) -> AResult<Vec<String>> {
+    let mut chars = content.trim().chars().peekable();
+    if chars.next() != Some('[') {
+        return Err("Exported state is not a JSON array".into());
+    }
+
+    let mut packages = Vec::new();
+    loop {
+        match chars.find(|c| !c.is_whitespace()) {
+            Some(']') if packages.is_empty() => break,
+            Some('"') => (),
+            _ => return Err("Exported state must only contain Strings".into()),
+        }
+
+        let mut package = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some(c @ ('"' | '\\' | '/')) => package.push(c),
+                    Some('n') => package.push('\n'),
+                    Some('t') => package.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        package.push(c.ok_or("Invalid unicode escape in exported state")?);
+                    }
+                    _ => return Err("Invalid escape in exported state".into()),
+                },
+                Some(c) => package.push(c),
+                None => return Err("Unterminated String in exported state".into()),
+            }
+        }
+        packages.push(package);
+
+        match chars.find(|c| !c.is_whitespace()) {
+            Some(',') => continue,
+            Some(']') => break,
+            _ => return Err("Expected , or ] in exported state".into()),
+        }
+    }
+
+    if chars.any(|c| !c.is_whitespace()) {
+        return Err("Unexpected content after the exported state".into());
+    }
+
+    cleanup_package_list(&mut packages);
+    Ok(packages)
+}
+
+/// Packages that are only on the first host and packages that are only on the second host.
+pub fn compare_hosts(a: &[String], b: &[String]) -> (Vec<String>, Vec<String>) {
+    (compare_lists_only_in_first(a, b), compare_lists_only_in_first(b, a))
+}
+
+/// Reads two exported package lists and prints the packages that are only on one of the hosts.
+/// The hosts are named after the file names.
+pub fn print_host_comparison(path_a: &str, path_b: &str) -> AResult<()> {
+    let read = |path: &str| -> AResult<(String, Vec<String>)> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let packages = parse_package_list_json(&content).map_err(|e| format!("{}: {}", path, e))?;
+        let host = Path::new(path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string());
+        Ok((host, packages))
+    };
+    let (host_a, packages_a) = read(path_a)?;
+    let (host_b, packages_b) = read(path_b)?;
+
+    let (only_a, only_b) = compare_hosts(&packages_a, &packages_b);
+    for (host, only) in [(host_a, only_a), (host_b, only_b)] {
+        println!("Only on {} ({}):", host, only.len());
+        for p in only {
+            println!("    {}", p);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cmd;
+
+    #[test]
+    fn exported_state_parsing() {
+        assert_eq!(
+            parse_package_list_json(r#"[ "b", "a\u0062", "a" ]"#).unwrap(),
+            ["a", "ab", "b"]
+        );
+    }
+
+    #[test]
+    fn packages_only_on_one_host() {
+        let (only_a, only_b) = compare_hosts(&cmd(&["a", "b", "c"]), &cmd(&["b", "d"]));
+        assert_eq!(only_a, ["a", "c"]);
+        assert_eq!(only_b, ["d"]);
+    }
+}
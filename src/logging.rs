@@ -0,0 +1,105 @@
+use crate::AResult;
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+/// Level of the diagnostics on stderr. The plan and other results on stdout are not affected.
+/// Errors are always printed, since they decide the exit code.
+/// All diagnostics go through the `log_*` macros, only the output of the planned commands is forwarded as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+pub fn parse_log_level(level: &str) -> AResult<LogLevel> {
+    match level.to_lowercase().as_str() {
+        "error" => Ok(LogLevel::Error),
+        "warn" | "warning" => Ok(LogLevel::Warn),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        _ => Err(format!("Unknown log level {}, must be error, warn, info or debug", level).into()),
+    }
+}
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Sets the run ID that prefixes the debug output, so that it can be matched with the journal and the report.
+/// Only the first call has an effect.
+pub fn set_run_id(run_id: &str) {
+    let _ = RUN_ID.set(run_id.to_string());
+}
+
+/// `[<run id>] `, or nothing before the run ID is set.
+pub fn run_id_prefix() -> String {
+    RUN_ID.get().map(|id| format!("[{}] ", id)).unwrap_or_default()
+}
+
+/// Prints an error to stderr. Errors are printed at every log level.
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        eprintln!($($arg)*)
+    };
+}
+
+/// Prints a warning to stderr, unless the log level is `error`.
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled($crate::logging::LogLevel::Warn) {
+            eprintln!("Warning: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+/// Prints progress, like written files, to stderr, if the log level is `info` or `debug`.
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled($crate::logging::LogLevel::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints details for debugging to stderr, like the query commands, if the log level is `debug`.
+/// Prefixed with the run ID, once it is set.
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::log_enabled($crate::logging::LogLevel::Debug) {
+            eprintln!("{}{}", $crate::logging::run_id_prefix(), format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {log_debug, log_error, log_info, log_warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_level_parsing() {
+        assert_eq!(parse_log_level("error").unwrap(), LogLevel::Error);
+        assert_eq!(parse_log_level("WARN").unwrap(), LogLevel::Warn);
+        assert_eq!(parse_log_level("warning").unwrap(), LogLevel::Warn);
+        assert_eq!(parse_log_level("Debug").unwrap(), LogLevel::Debug);
+        assert!(parse_log_level("loud").is_err());
+    }
+
+    #[test]
+    fn run_id_prefix_once_set() {
+        set_run_id("1-a");
+        set_run_id("2-b");
+        assert_eq!(run_id_prefix(), "[1-a] ");
+    }
+}
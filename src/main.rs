@@ -1,31 +1,281 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::error::Error;
-use std::fs::{self};
-use std::process::Command;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use toml::Table;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use toml::{Table, Value};
 
 pub type AResult<T> = Result<T, Box<dyn Error>>;
 pub type CommandVector = Vec<String>;
 
+mod command_synchronizer;
+mod config_schema;
+mod flatpak;
+mod global_config;
+mod host_compare;
+mod logging;
 mod package_synchronizer;
+mod report;
+mod self_test;
+mod signals;
+mod systemd_unit;
+#[cfg(test)]
+mod test_util;
+use command_synchronizer::*;
+use config_schema::*;
+use flatpak::*;
+use global_config::*;
+use host_compare::*;
+use logging::*;
 use package_synchronizer::*;
+use report::*;
+use self_test::*;
+use systemd_unit::*;
 
-#[allow(unused)]
-fn run_cmd(cmd: &[String]) -> AResult<()> {
+pub fn get_from_table<'a, T: toml::macros::Deserialize<'a>>(
+    table: &Table,
+    key: &str,
+    default: T,
+) -> Result<T, toml::de::Error> {
+    table
+        .get(key)
+        .map_or(Ok(default), |v: &Value| Value::try_into::<T>(v.clone()))
+}
+
+/// Number of stderr lines that are kept and shown when a command fails.
+pub const STDERR_TAIL_LINES: usize = 10;
+
+/// Error for a command that ran, but did not succeed.
+/// Contains the last lines of the stderr output of the command, to show the actual cause.
+#[derive(Debug, Clone)]
+pub struct CommandError {
+    pub cmd: String,
+    pub stderr_tail: Vec<String>,
+}
+
+impl CommandError {
+    pub fn new(cmd: &[String], stderr: &str) -> Self {
+        let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+        let skip = lines.len().saturating_sub(STDERR_TAIL_LINES);
+        CommandError {
+            cmd: cmd.join(" "),
+            stderr_tail: lines[skip..].iter().map(|l| l.to_string()).collect(),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Command did not succeed: {}", self.cmd)?;
+        for l in &self.stderr_tail {
+            write!(f, "\n    {}", l)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for CommandError {}
+
+/// Converts an error of starting a command. A missing program gets its own message,
+/// since it is usually a typo in a command override or a package manager that is not installed.
+pub fn spawn_error(program: &str, err: io::Error) -> Box<dyn Error> {
+    if err.kind() == io::ErrorKind::NotFound {
+        format!("Command not found: {}", program).into()
+    } else {
+        Box::new(err)
+    }
+}
+
+/// Runs a command and streams its stderr. SIGINT and SIGQUIT are caught while it runs, see `signals::SignalGuard`.
+fn run_cmd(cmd: &[String], env: &CommandEnv) -> AResult<()> {
     if cmd.is_empty() {
         return Ok(());
     }
 
-    let cmd_ret = Command::new(&cmd[0]).args(&cmd[1..]).status()?;
-    if !cmd_ret.success() {
-        return Err(Box::from("Command did not succeed"));
+    let _signal_guard = signals::SignalGuard::install();
+    let mut cmd_proc = Command::new(&cmd[0]);
+    env.apply(&mut cmd_proc);
+    signals::ignore_quit_in_child(&mut cmd_proc);
+    let mut cmd_proc = cmd_proc
+        .args(&cmd[1..])
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_error(&cmd[0], e))?;
+
+    // Stream stderr live, but remember the last lines in case the command fails.
+    let mut stderr_tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+    let stderr = io::BufReader::new(cmd_proc.stderr.take().expect("Stderr should be available!"));
+    for line in stderr.lines().map_while(Result::ok) {
+        // The output of the command, not a diagnostic of the synchronizer
+        eprintln!("{}", line);
+        if stderr_tail.len() == STDERR_TAIL_LINES {
+            stderr_tail.pop_front();
+        }
+        stderr_tail.push_back(line);
+    }
+
+    if !cmd_proc.wait()?.success() {
+        let stderr_tail: Vec<String> = stderr_tail.into();
+        return Err(Box::new(CommandError::new(cmd, &stderr_tail.join("\n"))));
     }
     Ok(())
 }
 
-fn pretty_print_cmds(cmd: &Vec<CommandVector>) {
-    for c in cmd {
-        println!("> {}", c.join(" "));
+/// Takes the lock that prevents two runs from changing the system at the same time.
+/// The lock is held until the returned file is closed, which also happens if the process is killed.
+pub fn acquire_instance_lock(path: &str) -> AResult<File> {
+    // A lock file created by another user can only be opened for reading, which is enough to lock it
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .or_else(|_| File::open(path))
+        .map_err(|e| format!("Could not open lock file {}: {}", path, e))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(format!(
+            "Another instance is already running ({} is locked). Use --no-lock to run anyway.",
+            path
+        )
+        .into()),
+        Err(TryLockError::Error(e)) => Err(format!("Could not lock {}: {}", path, e).into()),
+    }
+}
+
+/// Answer to the confirmation prompt of a single command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Confirmation {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Asks whether a command should be run. Asks again on invalid input.
+fn confirm_cmd(cmd: &[String]) -> AResult<Confirmation> {
+    loop {
+        print!("Run `{}`? [y/n/a/q] ", cmd.join(" "));
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            // Treat a closed stdin like quitting, instead of running anything unconfirmed
+            return Ok(Confirmation::Quit);
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Confirmation::Yes),
+            "n" | "no" => return Ok(Confirmation::No),
+            "a" | "all" => return Ok(Confirmation::All),
+            "q" | "quit" => return Ok(Confirmation::Quit),
+            _ => println!("Please answer y (yes), n (no), a (all remaining) or q (quit)."),
+        }
+    }
+}
+
+/// Phases whose commands remove packages, or mark them so that they are removed later.
+const REMOVAL_PHASES: [&str; 2] = ["Down", "Post"];
+
+/// Prints the removal commands among the given commands and asks once whether to proceed.
+/// Without a terminal on stdin, the removals are refused instead of blocking forever, unless `assume_yes` is set.
+fn confirm_removals(cmds: &[(&str, &CommandVector)], assume_yes: bool) -> AResult<()> {
+    let removals: Vec<&CommandVector> = cmds
+        .iter()
+        .filter(|(phase, _)| REMOVAL_PHASES.contains(phase))
+        .map(|(_, cmd)| *cmd)
+        .collect();
+    if removals.is_empty() || assume_yes {
+        return Ok(());
+    }
+
+    println!("These commands remove packages:");
+    for cmd in &removals {
+        println!("    > {}", cmd.join(" "));
+    }
+    if !io::stdin().is_terminal() {
+        return Err(
+            "Removals need to be confirmed, but stdin is not a terminal. Use --yes for unattended runs.".into(),
+        );
+    }
+
+    print!("Proceed? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err("Aborted by user".into()),
+    }
+}
+
+/// Runs the commands in order, each given with the name of its phase. Stops at the first failing command.
+/// With `confirm_each`, every command has to be confirmed first: 'n' skips the command,
+/// 'a' runs all remaining commands without asking and 'q' aborts.
+/// SIGQUIT lets the running command complete and then skips the remaining commands of its phase.
+/// SIGINT aborts after the running command, without starting another one.
+/// The outcome of every command that was run or skipped is appended to `executed`.
+fn run_cmds<'a>(
+    cmds: impl IntoIterator<Item = (&'a str, &'a CommandVector)>,
+    confirm_each: bool,
+    env: &CommandEnv,
+    executed: &mut Vec<ExecutedCommand>,
+) -> AResult<()> {
+    signals::reset();
+    let mut confirm_each = confirm_each;
+    let mut skipped_phase = None;
+    for (phase, cmd) in cmds {
+        let mut record = |outcome| {
+            executed.push(ExecutedCommand {
+                phase: phase.to_string(),
+                cmd: cmd.clone(),
+                outcome,
+            })
+        };
+        if skipped_phase == Some(phase) {
+            record(CommandOutcome::Skipped);
+            continue;
+        }
+        if confirm_each {
+            match confirm_cmd(cmd)? {
+                Confirmation::Yes => (),
+                Confirmation::No => {
+                    record(CommandOutcome::Skipped);
+                    continue;
+                }
+                Confirmation::All => confirm_each = false,
+                Confirmation::Quit => return Err("Aborted by user".into()),
+            }
+        }
+        let result = run_cmd(cmd, env);
+        if signals::take_interrupted() {
+            record(match result {
+                Ok(()) => CommandOutcome::Succeeded,
+                Err(e) => CommandOutcome::Failed(error_pretty_format(e.as_ref(), false)),
+            });
+            return Err(format!("Interrupted during {} command `{}`", phase, cmd.join(" ")).into());
+        }
+        if let Err(e) = result {
+            let e = error_pretty_format(e.as_ref(), false);
+            record(CommandOutcome::Failed(e.clone()));
+            return Err(format!("{} command `{}` failed: {}", phase, cmd.join(" "), e).into());
+        }
+        record(CommandOutcome::Succeeded);
+        if signals::take_skip_phase() {
+            log_warn!("Skipping the remaining {} commands.", phase);
+            skipped_phase = Some(phase);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the commands, numbered starting with `first_index`.
+/// The numbers can be used with `--run-command`.
+fn pretty_print_cmds(cmd: &[CommandVector], first_index: usize) {
+    for (i, c) in cmd.iter().enumerate() {
+        println!("{:>3}> {}", first_index + i, c.join(" "));
     }
 }
 
@@ -49,16 +299,33 @@ fn error_pretty_format(err: &dyn Error, skip_first: bool) -> String {
     }
 }
 
-fn find_config_tables(table: Table) -> Vec<Table> {
+/// Environment variable with the config path, used if no `--config` is given.
+const CONFIG_ENV_VAR: &str = "SCSYNC_CONFIG";
+
+/// Replaces a leading `~` with the home directory, for paths that were not expanded by a shell (e.g. quoted paths).
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix('~'), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", home, rest),
+        _ => path.to_string(),
+    }
+}
+
+/// Finds all synchronizer sections, together with their dotted key path.
+fn find_config_tables(table: Table, path: &str) -> Vec<(String, Table)> {
     if table.contains_key("type") {
-        return vec![table];
+        return vec![(path.to_string(), table)];
     }
 
     let mut arr = Vec::new();
-    for (_key, value) in table {
+    for (key, value) in table {
         match value {
             toml::Value::Table(subtable) => {
-                let new_arr = find_config_tables(subtable);
+                let key_path = if path.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                let new_arr = find_config_tables(subtable, &key_path);
                 arr.extend(new_arr);
             }
             _ => continue,
@@ -68,94 +335,2161 @@ fn find_config_tables(table: Table) -> Vec<Table> {
     arr
 }
 
-fn main() -> ExitCode {
-    let config_path = "config.toml".to_string();
+/// Reads the `type` of a synchronizer section, which selects the synchronizer.
+pub fn get_synchronizer_type(table: &Table) -> AResult<&str> {
+    match table.get("type") {
+        Some(Value::String(t)) => Ok(t),
+        Some(v) => Err(format!("\"type\" must be a string, found {}", v.type_str()).into()),
+        None => Err("\"type\" is missing".into()),
+    }
+}
 
-    let config = match fs::read_to_string(config_path) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Error reading config file: {}", error_pretty_format(&e, false));
+/// Supported synchronizer types.
+pub const SYNCHRONIZER_TYPES: [&str; 4] = ["pacman", "aur", "flatpak", "command"];
+
+/// Constructs the synchronizer selected by the `type` of the section.
+pub fn new_synchronizer(table: &Table, query_env: CommandEnv) -> AResult<Box<dyn SystemConfigSynchronizer>> {
+    match get_synchronizer_type(table)? {
+        "pacman" => Ok(Box::new(new_pacman(table, query_env)?)),
+        "aur" => Ok(Box::new(new_aur(table, query_env)?)),
+        "flatpak" => Ok(Box::new(new_flatpak(table, query_env)?)),
+        "command" => Ok(Box::new(new_command(table, query_env)?)),
+        t => Err(format!(
+            "Unknown synchronizer type: {} (supported: {})",
+            t,
+            SYNCHRONIZER_TYPES.join(", ")
+        )
+        .into()),
+    }
+}
+
+/// Keys of a `fallback` section.
+pub const FALLBACK_KEYS: [(&str, KeyType); 3] = [
+    ("type", KeyType::String),
+    ("name", KeyType::String),
+    ("backends", KeyType::Backends),
+];
+
+/// Synchronizer types that own all packages of their package manager.
+/// Two sections of such a type would remove each other's packages.
+const EXCLUSIVE_TYPES: [&str; 3] = ["pacman", "aur", "flatpak"];
+
+/// Errors if more than one section has the same exclusive type, naming the conflicting sections.
+/// Sections with `allow_multiple = true` are not counted.
+pub fn check_duplicate_types(config_tables: &[(String, Table)]) -> AResult<()> {
+    for sync_type in EXCLUSIVE_TYPES {
+        let sections: Vec<&str> = config_tables
+            .iter()
+            .filter(|(_, t)| t.get("type").and_then(Value::as_str) == Some(sync_type))
+            .filter(|(_, t)| !t.get("allow_multiple").and_then(Value::as_bool).unwrap_or(false))
+            .map(|(section, _)| section.as_str())
+            .collect();
+        if sections.len() > 1 {
+            return Err(format!(
+                "Sections [{}] all have type {} and would remove each other's packages. \
+                 Merge them, or set allow_multiple = true if this is intended.",
+                sections.join("], ["),
+                sync_type
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Checks if an executable with this name is in `PATH`. Paths are checked directly.
+fn binary_exists(name: &str) -> bool {
+    if name.contains('/') {
+        return Path::new(name).is_file();
+    }
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+/// The binary that has to exist for a backend of a `fallback` section to be selected:
+/// `pacman_cmd` for pacman, `helper_cmd` for aur, `flatpak` for flatpak, and for command the query command
+/// (`installed_cmd`), or the install command if the installed packages are read from a file.
+fn backend_binary(backend: &Table) -> AResult<String> {
+    let first_word = |key: &str| -> AResult<Option<String>> {
+        match backend.get(key) {
+            Some(v) => Ok(toml_value_to_cmd_array(key, v)?.into_iter().next()),
+            None => Ok(None),
+        }
+    };
+    let binary = match get_synchronizer_type(backend)? {
+        "pacman" => get_from_table(backend, "pacman_cmd", "pacman".to_string())?,
+        "aur" => get_from_table(backend, "helper_cmd", "paru".to_string())?,
+        "flatpak" => "flatpak".to_string(),
+        "command" => match first_word("installed_cmd")? {
+            Some(cmd) => cmd,
+            None => first_word("install_cmd")?.ok_or("install_cmd is missing")?,
+        },
+        t => {
+            return Err(format!(
+                "Unknown synchronizer type: {} (supported: {})",
+                t,
+                SYNCHRONIZER_TYPES.join(", ")
+            )
+            .into())
+        }
+    };
+    if binary.is_empty() {
+        return Err("The binary of a backend is empty".into());
+    }
+    Ok(binary)
+}
+
+/// Resolves a section of type `fallback` to the first of its `backends` whose binary exists
+/// (see `backend_binary`). Package names differ between distributions, so each alternative lists
+/// the packages in its own naming. Other distributions are supported with a command backend:
+///
+/// ```toml
+/// [tools]
+/// type = "fallback"
+/// backends = [
+///     { type = "pacman", packages = ["openssh"] },
+///     { type = "command", packages = ["openssh-client"], installed_cmd = "apt-mark showmanual",
+///       install_cmd = "sudo apt-get install -y", remove_cmd = "sudo apt-get remove -y" },
+/// ]
+/// ```
+///
+/// Other sections are returned unchanged.
+fn resolve_fallback(section: Table) -> AResult<Table> {
+    select_backend(section, binary_exists)
+}
+
+/// Like `resolve_fallback`, with the check whether a binary exists given.
+fn select_backend(section: Table, binary_exists: impl Fn(&str) -> bool) -> AResult<Table> {
+    if section.get("type").and_then(Value::as_str) != Some("fallback") {
+        return Ok(section);
+    }
+    for k in section.keys() {
+        if !FALLBACK_KEYS.iter().any(|(key, _)| key == k) {
+            return Err(format!("Unknown key: {}", k).into());
+        }
+    }
+
+    let backends = match section.get("backends") {
+        Some(Value::Array(b)) => b,
+        _ => return Err("backends is missing or not an Array!".into()),
+    };
+    // All alternatives are checked, not only up to the selected one, so that a mistake shows on every host
+    let mut alternatives = Vec::new();
+    for backend in backends {
+        let backend = backend
+            .as_table()
+            .ok_or("backends contains Elements that are not Tables.")?;
+        alternatives.push((backend, backend_binary(backend)?));
+    }
+    for (backend, binary) in alternatives {
+        if binary_exists(&binary) {
+            let mut backend = backend.clone();
+            // The name belongs to the fallback section, whichever backend is used
+            if let Some(name) = section.get("name") {
+                backend.entry("name".to_string()).or_insert(name.clone());
+            }
+            return Ok(backend);
+        }
+    }
+    Err("None of the backends is available".into())
+}
+
+/// Warns if the number of planned changes exceeds the configured threshold
+/// and errors if it exceeds the configured hard limit.
+/// The changes of all synchronizers are counted together, from the plan, so only the planned phases count.
+/// Returns whether the warning threshold is exceeded, so that the run can be confirmed (see `confirm_change_count`).
+fn check_change_count(global_config: &GlobalConfig, sync_reports: &[SynchronizerReport]) -> AResult<bool> {
+    let up_changes: usize = sync_reports.iter().filter_map(|r| r.up_changes).sum();
+    let down_changes: usize = sync_reports.iter().filter_map(|r| r.down_changes).sum();
+    let total_changes = up_changes + down_changes;
+
+    if let Some(limit) = global_config.change_hard_limit {
+        if total_changes > limit {
+            return Err(format!(
+                "{} planned changes ({} up, {} down) exceed the hard limit of {}",
+                total_changes, up_changes, down_changes, limit
+            )
+            .into());
+        }
+    }
+
+    if let Some(threshold) = global_config.change_warn_threshold {
+        if total_changes > threshold {
+            log_warn!(
+                "{} planned changes ({} up, {} down) exceed the warning threshold of {}. \
+                 This often indicates a broken config or query. Please check the commands below carefully.",
+                total_changes,
+                up_changes,
+                down_changes,
+                threshold
+            );
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Asks whether to run a plan whose changes exceed the warning threshold.
+/// Like for removals, a missing terminal refuses instead of blocking, unless `assume_yes` is set.
+fn confirm_change_count(assume_yes: bool) -> AResult<()> {
+    if assume_yes {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(
+            "The planned changes exceed the warning threshold, but stdin is not a terminal to confirm them. \
+                    Use --yes for unattended runs."
+                .into(),
+        );
+    }
+
+    print!("The planned changes exceed the warning threshold. Proceed? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err("Aborted by user".into()),
+    }
+}
+
+/// Phases whose commands download packages into the package cache.
+const DOWNLOAD_PHASES: [&str; 2] = ["Pre", "Up"];
+
+/// Whether any of the given commands, each given with the name of its phase, downloads packages.
+fn downloads_packages(cmds: &[(&str, &CommandVector)]) -> bool {
+    cmds.iter().any(|(phase, _)| DOWNLOAD_PHASES.contains(phase))
+}
+
+/// Errors if the filesystem that holds the package cache has less free space than configured.
+/// Uses `df`, to avoid a dependency for `statvfs`.
+fn check_free_space(global_config: &GlobalConfig) -> AResult<()> {
+    let min_free_space = match global_config.min_free_space {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+    let path = global_config
+        .free_space_path
+        .as_deref()
+        .unwrap_or(DEFAULT_FREE_SPACE_PATH);
+
+    let df_out = Command::new("df")
+        .args(["-B1", "--output=avail,size", path])
+        .stdin(Stdio::null())
+        .output()?;
+    if !df_out.status.success() {
+        let cmd: Vec<String> = ["df", "-B1", "--output=avail,size", path].map(String::from).into();
+        return Err(Box::new(CommandError::new(
+            &cmd,
+            &String::from_utf8_lossy(&df_out.stderr),
+        )));
+    }
+
+    // First line is the header, second line contains the values
+    let df_out = String::from_utf8_lossy(&df_out.stdout);
+    let values: Vec<u64> = df_out
+        .lines()
+        .nth(1)
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    let (avail, size) = match values[..] {
+        [avail, size] => (avail, size),
+        _ => return Err("Unexpected output of df".into()),
+    };
+
+    let enough_space = match min_free_space {
+        FreeSpace::Bytes(b) => avail >= b,
+        FreeSpace::Percent(p) => avail as u128 * 100 >= size as u128 * p as u128,
+    };
+    if !enough_space {
+        return Err(format!(
+            "Not enough free space on {}: {} of {} bytes available, but {:?} required",
+            path, avail, size, min_free_space
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Errors if the pacman database is locked and the plan is `applying`.
+/// Runs that only print cannot run into the lock, so for them it is only a warning.
+/// A stale lock is removed if requested, but only when `applying`.
+fn check_db_lock(
+    synchronizer: &PackageSynchronizer,
+    remove_stale_lock: bool,
+    applying: bool,
+    env: &CommandEnv,
+) -> AResult<()> {
+    let problem = match synchronizer.check_db_lock()? {
+        DbLockState::Free => return Ok(()),
+        DbLockState::Held => format!(
+            "{} exists and pacman is running. Wait for it to finish.",
+            synchronizer.get_db_lock_file()
+        ),
+        DbLockState::Stale if remove_stale_lock && applying => {
+            log_info!("Removing stale lock {}", synchronizer.get_db_lock_file());
+            return run_cmd(&synchronizer.get_remove_lock_cmd(), env);
+        }
+        DbLockState::Stale if remove_stale_lock => format!(
+            "{} is a stale lock. It is removed when the plan is applied.",
+            synchronizer.get_db_lock_file()
+        ),
+        DbLockState::Stale => format!(
+            "{} exists, but pacman is not running. The lock is probably stale, remove it with --remove-stale-lock.",
+            synchronizer.get_db_lock_file()
+        ),
+    };
+    if applying {
+        return Err(problem.into());
+    }
+    log_warn!("{}", problem);
+    Ok(())
+}
+
+/// Which phases are planned, selected by the subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum RunMode {
+    /// Install and remove.
+    #[default]
+    Sync,
+    /// Only install, without the down phase.
+    Up,
+    /// Only remove, without the up phase.
+    Down,
+    /// Only print the planned changes, without any commands.
+    Status,
+}
+
+impl RunMode {
+    fn runs_phase(&self, phase: &str) -> bool {
+        match self {
+            RunMode::Sync => true,
+            RunMode::Up => phase != "Down",
+            RunMode::Down => phase != "Up",
+            RunMode::Status => false,
+        }
+    }
+}
+
+/// Output format of the plan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum OutputFormat {
+    /// Readable preview, followed by running the plan.
+    #[default]
+    Text,
+    /// Only the plan as JSON (see `RunReport::to_json`), also for the status and the grouped preview.
+    /// Errors are JSON objects as well. Nothing is run.
+    Json,
+}
+
+/// Prints an error: as text on stderr, or as JSON object on stdout, where the consumer of the JSON output reads.
+fn print_error(format: OutputFormat, msg: &str) {
+    match format {
+        OutputFormat::Text => log_error!("{}", msg),
+        OutputFormat::Json => println!("{}", json_error(msg)),
+    }
+}
+
+/// Arguments given on the command line.
+#[derive(Debug, Clone, Default)]
+struct CliArgs {
+    /// Only print the packages of these groups, filtered by the blacklist.
+    list_groups_expansion: Option<Vec<String>>,
+    /// Run the diff engine against synthetic data instead of the system.
+    self_test: bool,
+    /// Only process synchronizers of this type.
+    only_type: Option<String>,
+    /// Skip synchronizers of these types. Applied after `only_type`.
+    exclude_types: Vec<String>,
+    /// Print a detailed trace of how the commands are computed.
+    explain: bool,
+    /// Write a summary of the run to this file.
+    report: Option<String>,
+    /// Only run the command with this number of the printed plan. In dry mode, the command is only printed.
+    run_command: Option<usize>,
+    /// Ask for confirmation before running each command.
+    confirm_each: bool,
+    /// Do not ask for confirmation before removals.
+    assume_yes: bool,
+    /// Run the plan even if the global config sets `dry_mode`.
+    apply: bool,
+    /// Print additional hints about the config, and the query commands unless another log level is set.
+    /// Twice also prints the package sets of the planned changes.
+    verbose: u8,
+    /// Level of the diagnostics on stderr.
+    log_level: Option<LogLevel>,
+    /// Do not take the lock that prevents two runs at the same time.
+    no_lock: bool,
+    /// Remove the pacman database lock, if no pacman process is running.
+    remove_stale_lock: bool,
+    /// Allow removing the package that provides the sudo command.
+    allow_remove_sudo: bool,
+    /// Allow removals even if a synchronizer has an empty config.
+    allow_empty_config: bool,
+    /// Only warn about configured groups without packages.
+    allow_empty_groups: bool,
+    /// Ignore the removal limits of the synchronizers.
+    force: bool,
+    /// Only install and mark as explicit, never remove or mark as dependency.
+    no_remove: bool,
+    /// Print the resolved command templates of the synchronizer instead of running.
+    print_meta: bool,
+    /// Config files to read, merged in order. Defaults to `config.toml`.
+    config_paths: Vec<String>,
+    /// Write the plan of each synchronizer into its own file in this directory.
+    output_dir: Option<String>,
+    /// Print the JSON Schema of the config format instead of running.
+    json_schema: bool,
+    /// Generate a systemd service and timer unit instead of running.
+    generate_unit: bool,
+    /// `OnCalendar` schedule of the generated timer.
+    schedule: Option<String>,
+    /// Generate a user unit instead of a system unit.
+    user_unit: bool,
+    /// Write the generated units into this directory instead of printing them.
+    unit_dir: Option<String>,
+    /// Print the explicitly installed packages that are not in the config.
+    audit: bool,
+    /// Print the packages whose install reason does not match the config.
+    verify_reasons: bool,
+    /// Only audit packages that were installed since the last boot.
+    since_boot: bool,
+    /// Print packages of the config that would be installed as dependencies anyway.
+    prune_config: bool,
+    /// Remove the packages found by `prune_config` from the config files.
+    write: bool,
+    /// Print the planned changes grouped by action across all synchronizers, instead of the commands.
+    group_by_action: bool,
+    /// Order the packages of each action in the grouped view by synchronizer instead of by name.
+    preview_sort_by_source: bool,
+    /// Compute the plan this many times and print the durations of each phase instead of running.
+    benchmark: Option<usize>,
+    /// Print how the config state of each synchronizer is assembled instead of running.
+    print_provenance_tree: bool,
+    /// Print a config with the currently installed packages instead of running.
+    generate: bool,
+    /// Compare two exported package lists instead of running.
+    compare_hosts: Option<(String, String)>,
+    /// Only print the number of commands of each phase instead of the commands.
+    no_preview: bool,
+    /// Record the plan in the journal even in dry mode.
+    journal_dry: bool,
+    /// Print the dependencies that the installs would pull in.
+    show_new_deps: bool,
+    /// Phases to plan.
+    mode: RunMode,
+    /// Format of the plan and the errors.
+    format: OutputFormat,
+    /// Write the plan as a shell script to this file instead of running it.
+    script: Option<String>,
+    /// Fail if the hash of the plan differs from this one.
+    expect_hash: Option<String>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(args: I) -> AResult<CliArgs> {
+    let mut cli_args = CliArgs::default();
+    let mut args = args.peekable();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--list-groups-expansion" => {
+                let mut groups = Vec::new();
+                while let Some(g) = args.next_if(|a| !a.starts_with('-')) {
+                    groups.push(g);
+                }
+                if groups.is_empty() {
+                    return Err("--list-groups-expansion needs at least one group".into());
+                }
+                cli_args.list_groups_expansion = Some(groups);
+            }
+            "--self-test" => cli_args.self_test = true,
+            "--only" => match args.next() {
+                Some(t) => cli_args.only_type = Some(t),
+                None => return Err("--only needs a synchronizer type".into()),
+            },
+            "--exclude-type" => match args.next() {
+                Some(t) => cli_args.exclude_types.push(t),
+                None => return Err("--exclude-type needs a synchronizer type".into()),
+            },
+            "explain" | "--explain" => cli_args.explain = true,
+            "--run-command" => match args.next().map(|i| i.parse::<usize>()) {
+                Some(Ok(i)) if i > 0 => cli_args.run_command = Some(i),
+                _ => return Err("--run-command needs the positive number of a command".into()),
+            },
+            "--config" | "-c" => match args.next() {
+                Some(p) => cli_args.config_paths.push(p),
+                None => return Err("--config needs a file path".into()),
+            },
+            "--group-by" => match args.next().as_deref() {
+                Some("action") => cli_args.group_by_action = true,
+                Some("synchronizer") => cli_args.group_by_action = false,
+                _ => return Err("--group-by needs either action or synchronizer".into()),
+            },
+            "--preview-sort" => match args.next().as_deref() {
+                Some("name") => cli_args.preview_sort_by_source = false,
+                Some("source") => cli_args.preview_sort_by_source = true,
+                _ => return Err("--preview-sort needs either name or source".into()),
+            },
+            "--format" => match args.next().as_deref() {
+                Some("text") => cli_args.format = OutputFormat::Text,
+                Some("json") => cli_args.format = OutputFormat::Json,
+                _ => return Err("--format needs either text or json".into()),
+            },
+            "--script" => match args.next() {
+                Some(p) => cli_args.script = Some(p),
+                None => return Err("--script needs a file path".into()),
+            },
+            "--output-dir" => match args.next() {
+                Some(d) => cli_args.output_dir = Some(d),
+                None => return Err("--output-dir needs a directory".into()),
+            },
+            "--json-schema" => cli_args.json_schema = true,
+            "--compare-hosts" => match (args.next(), args.next()) {
+                (Some(a), Some(b)) => cli_args.compare_hosts = Some((a, b)),
+                _ => return Err("--compare-hosts needs two exported package lists".into()),
+            },
+            "--no-remove" => cli_args.no_remove = true,
+            "--no-preview" => cli_args.no_preview = true,
+            "--journal-dry" => cli_args.journal_dry = true,
+            "--show-new-deps" => cli_args.show_new_deps = true,
+            "--print-provenance-tree" => cli_args.print_provenance_tree = true,
+            "--benchmark" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => cli_args.benchmark = Some(n),
+                _ => return Err("--benchmark needs a positive number of iterations".into()),
+            },
+            "--expect-hash" => match args.next() {
+                Some(h) => cli_args.expect_hash = Some(h.to_lowercase()),
+                None => return Err("--expect-hash needs a hash".into()),
+            },
+            "generate-unit" => cli_args.generate_unit = true,
+            "generate" => cli_args.generate = true,
+            "sync" => cli_args.mode = RunMode::Sync,
+            "up" => cli_args.mode = RunMode::Up,
+            "down" => cli_args.mode = RunMode::Down,
+            "status" => cli_args.mode = RunMode::Status,
+            "--schedule" => match args.next() {
+                Some(sc) => cli_args.schedule = Some(sc),
+                None => return Err("--schedule needs an OnCalendar value, like daily".into()),
+            },
+            "--user" => cli_args.user_unit = true,
+            "--unit-dir" => match args.next() {
+                Some(d) => cli_args.unit_dir = Some(d),
+                None => return Err("--unit-dir needs a directory".into()),
+            },
+            "audit" => cli_args.audit = true,
+            "verify-reasons" => cli_args.verify_reasons = true,
+            "--since-boot" => cli_args.since_boot = true,
+            "--prune-config" => cli_args.prune_config = true,
+            "--write" => cli_args.write = true,
+            "--confirm-each" => cli_args.confirm_each = true,
+            "--yes" | "-y" => cli_args.assume_yes = true,
+            "--apply" => cli_args.apply = true,
+            "--verbose" | "-v" => cli_args.verbose += 1,
+            "-vv" => cli_args.verbose += 2,
+            "--no-lock" => cli_args.no_lock = true,
+            "--log-level" => match args.next() {
+                Some(l) => cli_args.log_level = Some(parse_log_level(&l)?),
+                None => return Err("--log-level needs a level".into()),
+            },
+            "--remove-stale-lock" => cli_args.remove_stale_lock = true,
+            "--allow-remove-sudo" => cli_args.allow_remove_sudo = true,
+            "--allow-empty-config" => cli_args.allow_empty_config = true,
+            "--allow-empty-groups" => cli_args.allow_empty_groups = true,
+            "--force" => cli_args.force = true,
+            "--print-meta" => cli_args.print_meta = true,
+            "--report" => match args.next() {
+                Some(p) => cli_args.report = Some(p),
+                None => return Err("--report needs a file path".into()),
+            },
+            _ => return Err(format!("Unknown argument: {}", arg).into()),
+        }
+    }
+
+    Ok(cli_args)
+}
+
+/// Whether the run executes planned commands, as opposed to only printing them.
+/// Explanations, the status, JSON output, grouped previews and scripts only print, like the dry mode.
+fn executes_commands(cli_args: &CliArgs, dry_mode: bool) -> bool {
+    !dry_mode
+        && !cli_args.explain
+        && cli_args.mode != RunMode::Status
+        && cli_args.format == OutputFormat::Text
+        && !cli_args.group_by_action
+        && cli_args.script.is_none()
+}
+
+/// The first given mode that only prints text. These cannot be combined with `--format json`,
+/// which is supported for the plan, the status and the grouped preview.
+fn text_only_mode(cli_args: &CliArgs) -> Option<&'static str> {
+    [
+        (cli_args.self_test, "--self-test"),
+        (cli_args.compare_hosts.is_some(), "--compare-hosts"),
+        (cli_args.generate, "generate"),
+        (cli_args.generate_unit, "generate-unit"),
+        (cli_args.list_groups_expansion.is_some(), "--list-groups-expansion"),
+        (cli_args.print_meta, "--print-meta"),
+        (cli_args.audit, "audit"),
+        (cli_args.print_provenance_tree, "--print-provenance-tree"),
+        (cli_args.benchmark.is_some(), "--benchmark"),
+        (cli_args.verify_reasons, "verify-reasons"),
+        (cli_args.prune_config, "--prune-config"),
+        (cli_args.explain, "explain"),
+        (cli_args.script.is_some(), "--script"),
+    ]
+    .into_iter()
+    .find(|(set, _)| *set)
+    .map(|(_, mode)| mode)
+}
+
+/// Prints the planned commands of all phases of a synchronizer and records them in the report.
+type PhaseFn = fn(&dyn SystemConfigSynchronizer) -> AResult<Vec<CommandVector>>;
+
+/// The phases of a synchronizer, in the order they run.
+const PHASES: [(&str, PhaseFn); 4] = [
+    ("Pre", |s| s.get_pre_cmds()),
+    ("Up", |s| s.get_up_cmds()),
+    ("Down", |s| s.get_down_cmds()),
+    ("Post", |s| s.get_post_cmds()),
+];
+
+/// Computes the plan of each synchronizer and records the phases of the mode in its report.
+fn plan_phases(
+    synchronizers: &[(&str, &dyn SystemConfigSynchronizer)],
+    sync_reports: &mut [SynchronizerReport],
+    mode: RunMode,
+) -> AResult<()> {
+    let phases: Vec<&str> = PHASES
+        .iter()
+        .map(|(phase, _)| *phase)
+        .filter(|phase| mode.runs_phase(phase))
+        .collect();
+    for ((_, synchronizer), sync_report) in synchronizers.iter().zip(sync_reports.iter_mut()) {
+        let plan = synchronizer.plan(&phases)?;
+        sync_report.up_changes = phases.contains(&"Up").then_some(plan.up_changes);
+        sync_report.down_changes = phases.contains(&"Down").then_some(plan.down_changes);
+        for (phase, cmds) in plan.into_phases() {
+            if phases.contains(&phase) {
+                sync_report.phases.push((phase.to_string(), cmds));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the planned commands of each phase, numbered in the order they run, so the numbers can be used with `--run-command`.
+/// Without `preview`, only the number of commands of each phase is printed.
+/// If the planning failed, the phases that were planned so far are printed.
+fn print_phases(sync_reports: &[SynchronizerReport], preview: bool) {
+    let phase_names: Vec<&str> = sync_reports
+        .first()
+        .map(|r| r.phases.iter().map(|(phase, _)| phase.as_str()).collect())
+        .unwrap_or_default();
+
+    let mut next_index = 1;
+    for (i, phase) in phase_names.iter().enumerate() {
+        if preview {
+            println!("{} Commands:", phase);
+        }
+        for sync_report in sync_reports {
+            let Some((_, cmds)) = sync_report.phases.get(i) else {
+                continue;
+            };
+            if !preview {
+                println!("{} Commands ({}): {}", phase, sync_report.name, cmds.len());
+            } else if sync_reports.len() > 1 {
+                println!("  # {}", sync_report.name);
+                pretty_print_cmds(cmds, next_index);
+            } else {
+                pretty_print_cmds(cmds, next_index);
+            }
+            next_index += cmds.len();
+        }
+    }
+}
+
+/// Prints the notes of the config packages that the planned commands touch.
+fn print_plan_notes(pacman_synchronizers: &[(&str, &PackageSynchronizer)], sync_reports: &[SynchronizerReport]) {
+    let mut notes = Vec::new();
+    for sync_report in sync_reports {
+        let Some((_, synchronizer)) = pacman_synchronizers.iter().find(|(name, _)| *name == sync_report.name) else {
+            continue;
+        };
+        for (_, cmds) in &sync_report.phases {
+            for arg in cmds.iter().flatten() {
+                // Repository qualified targets, like extra/firefox
+                let package = arg.rsplit('/').next().unwrap_or(arg);
+                if let Some(note) = synchronizer.get_note(package) {
+                    let line = format!("    {}: {}", package, note);
+                    if !notes.contains(&line) {
+                        notes.push(line);
+                    }
+                }
+            }
+        }
+    }
+    if !notes.is_empty() {
+        println!("Notes:");
+        for n in notes {
+            println!("{}", n);
+        }
+    }
+}
+
+/// Computes the plan the given number of times and prints the min/mean/max duration of each phase.
+/// Only the query commands are run, so this is read only. The query cache of the pacman synchronizer
+/// is cleared before each iteration, so that every iteration runs the query commands like a fresh run.
+fn benchmark_phases(synchronizer: &dyn SystemConfigSynchronizer, iterations: usize) -> AResult<()> {
+    let mut durations: Vec<Vec<Duration>> = vec![Vec::new(); PHASES.len()];
+    for _ in 0..iterations {
+        if let Some(p) = synchronizer.as_any().downcast_ref::<PackageSynchronizer>() {
+            p.clear_query_cache();
+        }
+        for ((_, get_cmds), d) in PHASES.iter().zip(durations.iter_mut()) {
+            let start = Instant::now();
+            get_cmds(synchronizer)?;
+            d.push(start.elapsed());
+        }
+    }
+
+    println!("Planning {} times:", iterations);
+    for ((name, _), d) in PHASES.iter().zip(&durations) {
+        let min = d.iter().min().copied().unwrap_or_default();
+        let max = d.iter().max().copied().unwrap_or_default();
+        let mean = d.iter().sum::<Duration>() / iterations as u32;
+        println!(
+            "    {:<5} min {:>9.3} ms, mean {:>9.3} ms, max {:>9.3} ms",
+            name,
+            min.as_secs_f64() * 1000.0,
+            mean.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0
+        );
+    }
+    Ok(())
+}
+
+/// The command with the given number of the printed plan, with the name of its phase.
+fn select_cmd(report: &RunReport, index: usize) -> AResult<(&str, &CommandVector)> {
+    Ok(report
+        .cmds_in_run_order()
+        .into_iter()
+        .nth(index - 1)
+        .ok_or("No command with this number in the plan")?)
+}
+
+/// Runs only the command with the given number of the printed plan.
+fn run_single_cmd(
+    report: &RunReport,
+    index: usize,
+    confirm_each: bool,
+    assume_yes: bool,
+    env: &CommandEnv,
+    executed: &mut Vec<ExecutedCommand>,
+) -> AResult<()> {
+    let (phase, cmd) = select_cmd(report, index)?;
+    println!("Running:");
+    pretty_print_cmds(std::slice::from_ref(cmd), index);
+    confirm_removals(&[(phase, cmd)], assume_yes)?;
+    run_cmds([(phase, cmd)], confirm_each, env, executed)
+}
+
+/// Runs all planned commands, phase by phase across all synchronizers.
+/// Removals have to be confirmed first, unless `assume_yes` is set.
+fn apply_plan(
+    report: &RunReport,
+    confirm_each: bool,
+    assume_yes: bool,
+    env: &CommandEnv,
+    executed: &mut Vec<ExecutedCommand>,
+) -> AResult<()> {
+    let cmds = report.cmds_in_run_order();
+    confirm_removals(&cmds, assume_yes)?;
+    run_cmds(cmds, confirm_each, env, executed)
+}
+
+/// Writes the report, if one was requested. Returns the exit code for the result recorded in the report,
+/// so that the report is written after the commands ran and agrees with the exit code.
+fn finish_run(report: &RunReport, report_path: Option<&str>) -> ExitCode {
+    if let Some(path) = report_path {
+        if let Err(e) = report.write_to(path) {
+            log_error!("Error writing report: {}", error_pretty_format(e.as_ref(), false));
             return ExitCode::FAILURE;
         }
+    }
+    if report.succeeded() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints the error, records it in the report and finishes the run.
+fn fail_run(report: &mut RunReport, report_path: Option<&str>, msg: String) -> ExitCode {
+    log_error!("{}", msg);
+    report.errors.push(msg);
+    finish_run(report, report_path)
+}
+
+/// Prints the planned changes of all synchronizers, grouped by action instead of by synchronizer.
+/// Within each action, the packages are sorted by name, or kept in the order of the synchronizers if `by_source` is set.
+fn print_grouped_by_action(synchronizers: &[(&str, &dyn SystemConfigSynchronizer)], by_source: bool) -> AResult<()> {
+    // Keep the actions in the order they first appear
+    let mut actions: Vec<(String, Vec<String>)> = Vec::new();
+    for (name, synchronizer) in synchronizers {
+        for (action, packages) in synchronizer.get_planned_changes()? {
+            let entries = packages.iter().map(|p| format!("{} ({})", p, name));
+            match actions.iter_mut().find(|(a, _)| *a == action) {
+                Some((_, l)) => l.extend(entries),
+                None => actions.push((action, entries.collect())),
+            }
+        }
+    }
+
+    for (action, mut entries) in actions {
+        if !by_source {
+            entries.sort();
+        }
+        println!("Everything to {} ({}):", action.to_lowercase(), entries.len());
+        for e in entries {
+            println!("    {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Removes the given packages from the `packages` arrays of a config file.
+/// Only works for arrays with one package per line, so that comments and formatting are kept.
+/// Returns the packages that were removed.
+fn remove_packages_from_config_file(path: &str, packages: &[String]) -> AResult<Vec<String>> {
+    let content = fs::read_to_string(path)?;
+    let mut removed = Vec::new();
+    let mut in_packages = false;
+    let mut lines = Vec::new();
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if !in_packages && trimmed.starts_with("packages") && trimmed.contains('[') {
+            in_packages = !trimmed.contains(']');
+        } else if in_packages {
+            let entry = trimmed.trim_end_matches(',').trim_matches('"');
+            if trimmed.starts_with('"') && packages.iter().any(|p| p == entry) {
+                removed.push(entry.to_string());
+                continue;
+            }
+            if trimmed.starts_with(']') {
+                in_packages = false;
+            }
+        }
+        lines.push(line);
+    }
+
+    if !removed.is_empty() {
+        lines.push("");
+        fs::write(path, lines.join("\n"))?;
+    }
+    Ok(removed)
+}
+
+/// Prints the packages that can be pruned from the config and removes them from the config files if requested.
+fn prune_config(synchronizer: &PackageSynchronizer, config_paths: &[String], write: bool) -> AResult<()> {
+    let redundant = synchronizer.find_redundant_packages()?;
+    println!(
+        "{} packages are dependencies of other packages in the config:",
+        redundant.len()
+    );
+    for p in &redundant {
+        println!("    {}", p);
+    }
+    if !write || redundant.is_empty() {
+        return Ok(());
+    }
+
+    let mut removed = Vec::new();
+    for path in config_paths {
+        for p in remove_packages_from_config_file(path, &redundant)? {
+            log_info!("Removed {} from {}", p, path);
+            removed.push(p);
+        }
+    }
+    let not_removed: Vec<&str> = redundant
+        .iter()
+        .filter(|p| !removed.contains(p))
+        .map(String::as_str)
+        .collect();
+    if !not_removed.is_empty() {
+        log_warn!(
+            "Could not remove automatically, please remove manually: {}",
+            not_removed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Prints the explanation of a synchronizer, section by section.
+fn print_explanation(synchronizer: &dyn SystemConfigSynchronizer) -> AResult<()> {
+    let mut sections = synchronizer.get_explanation()?;
+    if let Some(p) = synchronizer.as_any().downcast_ref::<PackageSynchronizer>() {
+        sections.push(("Reasons".to_string(), p.get_change_reasons()?));
+    }
+    for (name, lines) in sections {
+        println!("{} ({}):", name, lines.len());
+        for l in lines {
+            println!("    {}", l);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the packages that the given groups would add to the config, with the blacklist applied.
+fn print_groups_expansion(synchronizer: &PackageSynchronizer, groups: &[String]) -> AResult<()> {
+    let packages = synchronizer.expand_groups(groups)?;
+    for p in &packages {
+        println!("{}", p);
+    }
+    println!("{} packages in {}", packages.len(), groups.join(", "));
+    Ok(())
+}
+
+/// Canonicalizes a config file path and checks that the file is not already in the chain of files
+/// that include each other. On a cycle, the error names the files of the cycle.
+/// Returns the canonical path, which is then pushed to the chain.
+pub fn check_include_cycle(chain: &[PathBuf], path: &str) -> AResult<PathBuf> {
+    let canonical = fs::canonicalize(path).map_err(|e| format!("Could not resolve config file {}: {}", path, e))?;
+    if let Some(start) = chain.iter().position(|p| *p == canonical) {
+        let cycle: Vec<String> = chain[start..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(format!("Circular include: {}", cycle.join(" -> ")).into());
+    }
+    Ok(canonical)
+}
+
+/// Merges a config file into the configs of the previous files.
+/// Synchronizer sections accumulate, but a section can only be defined in one file.
+/// Other tables (like `global`) are merged recursively, where later values override earlier ones.
+/// Without `allow_override`, a value that is already defined is an error instead.
+pub fn merge_config_tables(base: &mut Table, overlay: Table, path: &str, allow_override: bool) -> AResult<()> {
+    for (key, value) in overlay {
+        let key_path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", path, key)
+        };
+
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => {
+                if base_table.contains_key("type") || overlay_table.contains_key("type") {
+                    return Err(format!(
+                        "Synchronizer section {} is already defined in a previous config file. Synchronizer sections are not merged.",
+                        key_path
+                    )
+                    .into());
+                }
+                merge_config_tables(base_table, overlay_table, &key_path, allow_override)?;
+            }
+            (Some(_), _) if !allow_override => {
+                return Err(format!(
+                    "{} is already defined in another config file. Included files cannot override values.",
+                    key_path
+                )
+                .into());
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a config file and merges it into `config`, followed by the files it includes with `include = [...]`.
+/// Included paths are relative to the directory of the including file.
+/// `chain` holds the files that are currently being read, to detect circular includes.
+fn load_config_file(
+    path: &str,
+    chain: &mut Vec<PathBuf>,
+    config: &mut Table,
+    section_sources: &mut BTreeMap<String, String>,
+    allow_override: bool,
+) -> AResult<()> {
+    // Read first, so that a missing file is reported as such instead of as unresolvable
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Error reading config file {}: {}", path, error_pretty_format(&e, false)))?;
+    let canonical = check_include_cycle(chain, path)?;
+    let mut table = content
+        .parse::<Table>()
+        .map_err(|e| format!("Error reading config file {}: {}", path, error_pretty_format(&e, false)))?;
+    let includes: Vec<String> = match table.remove("include") {
+        Some(v) => v
+            .try_into()
+            .map_err(|_| format!("include in {} is not an Array of Strings", path))?,
+        None => Vec::new(),
     };
 
-    let config = match config.parse::<Table>() {
-        Ok(c) => c,
+    for (section, _) in find_config_tables(table.clone(), "") {
+        section_sources.insert(section, path.to_string());
+    }
+    merge_config_tables(config, table, "", allow_override).map_err(|e| {
+        format!(
+            "Error merging config file {}: {}",
+            path,
+            error_pretty_format(e.as_ref(), false)
+        )
+    })?;
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+    chain.push(canonical);
+    for include in includes {
+        let include_path = dir.join(expand_home(&include));
+        load_config_file(&include_path.to_string_lossy(), chain, config, section_sources, false)?;
+    }
+    chain.pop();
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli_args = match parse_args(std::env::args().skip(1)) {
+        Ok(a) => a,
         Err(e) => {
-            eprintln!("Error reading config file: {}", error_pretty_format(&e, false));
+            log_error!("Error parsing arguments: {}", error_pretty_format(e.as_ref(), false));
             return ExitCode::FAILURE;
         }
     };
 
-    let config_tables = find_config_tables(config);
-    let pacman_config = match config_tables.first() {
-        Some(x) => x,
-        _ => {
-            eprintln!("Could not find valid pacman configuration.");
+    if let (OutputFormat::Json, Some(mode)) = (cli_args.format, text_only_mode(&cli_args)) {
+        print_error(
+            cli_args.format,
+            &format!("Error: {} has no JSON output. Use --format text.", mode),
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if cli_args.self_test {
+        return if run_self_test() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+
+    if cli_args.json_schema {
+        print!("{}", config_json_schema());
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some((a, b)) = &cli_args.compare_hosts {
+        if let Err(e) = print_host_comparison(a, b) {
+            log_error!("Error comparing hosts: {}", error_pretty_format(e.as_ref(), false));
             return ExitCode::FAILURE;
         }
-    };
+        return ExitCode::SUCCESS;
+    }
 
-    let pacman_config = match new_pacman(pacman_config) {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("Error in Pacman Config: {}", error_pretty_format(e.as_ref(), false));
+    if cli_args.generate {
+        // No config is read, so the queries run with the default environment
+        let generated =
+            new_pacman(&Table::new(), GlobalConfig::default().query_env()).and_then(|p| p.generate_config());
+        match generated {
+            Ok(c) => print!("{}", c),
+            Err(e) => {
+                log_error!("Error generating config: {}", error_pretty_format(e.as_ref(), false));
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    // Explicit paths first, then the environment variable, then the current directory
+    let config_paths: Vec<String> = if !cli_args.config_paths.is_empty() {
+        cli_args.config_paths.iter().map(|p| expand_home(p)).collect()
+    } else if let Some(p) = std::env::var_os(CONFIG_ENV_VAR) {
+        let p = expand_home(&p.to_string_lossy());
+        // A typo in the variable should not silently run against another config
+        if !Path::new(&p).is_file() {
+            print_error(
+                cli_args.format,
+                &format!("Error: {} points to {}, which does not exist", CONFIG_ENV_VAR, p),
+            );
             return ExitCode::FAILURE;
         }
+        vec![p]
+    } else {
+        vec!["config.toml".to_string()]
     };
-    println!("Pacman Config: {:?}", pacman_config);
 
-    let pre_cmds = match pacman_config.get_pre_cmds() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!(
-                "Error running query commands: {}",
+    if cli_args.generate_unit {
+        let schedule = cli_args.schedule.as_deref().unwrap_or("daily");
+        let units = std::env::current_exe()
+            .map_err(Box::from)
+            .and_then(|exe| generate_units(&exe, &config_paths, schedule, cli_args.user_unit))
+            .and_then(|(service, timer)| {
+                output_units(&service, &timer, cli_args.unit_dir.as_deref(), cli_args.user_unit)
+            });
+        if let Err(e) = units {
+            log_error!(
+                "Error generating systemd units: {}",
                 error_pretty_format(e.as_ref(), false)
             );
             return ExitCode::FAILURE;
         }
-    };
-    println!("Pre Commands:");
-    pretty_print_cmds(&pre_cmds);
+        return ExitCode::SUCCESS;
+    }
 
-    let up_cmds = match pacman_config.get_up_cmds() {
-        Ok(c) => c,
+    let mut config = Table::new();
+    // Config file of each synchronizer section, to point to it in error messages.
+    // Sections are not merged, so each section comes from exactly one file.
+    let mut section_sources: BTreeMap<String, String> = BTreeMap::new();
+    for config_path in &config_paths {
+        if let Err(e) = load_config_file(config_path, &mut Vec::new(), &mut config, &mut section_sources, true) {
+            print_error(cli_args.format, &error_pretty_format(e.as_ref(), false));
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // Global keys are either top level keys (that are not tables) or keys of the `[global]` table
+    let global_keys: Vec<String> = config
+        .iter()
+        .filter(|(_, v)| !v.is_table())
+        .map(|(k, _)| k.clone())
+        .collect();
+    let mut global_table = Table::new();
+    for k in global_keys {
+        if let Some(v) = config.remove(&k) {
+            global_table.insert(k, v);
+        }
+    }
+    let global_config = match config.remove("global") {
+        Some(Value::Table(t)) => match t.keys().find(|k| global_table.contains_key(*k)) {
+            Some(k) => Err(format!("{} is set both at the top level and in [global]", k).into()),
+            None => {
+                for (k, v) in t {
+                    global_table.insert(k, v);
+                }
+                new_global_config(&global_table)
+            }
+        },
+        Some(_) => Err("global is not a table".into()),
+        None => new_global_config(&global_table),
+    };
+    let mut global_config = match global_config {
+        Ok(g) => g,
         Err(e) => {
-            eprintln!(
-                "Error running query commands: {}",
-                error_pretty_format(e.as_ref(), false)
+            print_error(
+                cli_args.format,
+                &format!("Error in Global Config: {}", error_pretty_format(e.as_ref(), false)),
             );
             return ExitCode::FAILURE;
         }
     };
-    println!("Up Commands:");
-    pretty_print_cmds(&up_cmds);
+    if cli_args.apply {
+        global_config.dry_mode = false;
+    }
+
+    // Values of RUST_LOG with filters for single modules are ignored
+    let env_log_level = std::env::var("RUST_LOG").ok().and_then(|l| parse_log_level(&l).ok());
+    let default_log_level = if cli_args.verbose > 0 {
+        LogLevel::Debug
+    } else {
+        LogLevel::Info
+    };
+    set_log_level(
+        cli_args
+            .log_level
+            .or(env_log_level)
+            .or(global_config.log_level)
+            .unwrap_or(default_log_level),
+    );
 
-    let down_cmds = match pacman_config.get_down_cmds() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!(
+    global_config.run_id = generate_run_id();
+    set_run_id(&global_config.run_id);
+
+    // Only runs that change the system have to exclude each other
+    let _instance_lock = if executes_commands(&cli_args, global_config.dry_mode) && !cli_args.no_lock {
+        let lock_file = global_config.lock_file.as_deref().unwrap_or(DEFAULT_LOCK_FILE);
+        match acquire_instance_lock(lock_file) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                print_error(
+                    cli_args.format,
+                    &format!("Error: {}", error_pretty_format(e.as_ref(), false)),
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    let top_level_tables: Vec<String> = config
+        .iter()
+        .filter(|(_, v)| v.is_table())
+        .map(|(k, _)| k.clone())
+        .collect();
+    let mut config_tables = Vec::new();
+    for (section, table) in find_config_tables(config, "") {
+        let resolved = match get_synchronizer_type(&table) {
+            Ok(_) => resolve_fallback(table),
+            Err(e) => Err(e),
+        };
+        match resolved {
+            Ok(t) => config_tables.push((section, t)),
+            Err(e) => {
+                print_error(
+                    cli_args.format,
+                    &format!(
+                        "Error in section {}: {}",
+                        section,
+                        error_pretty_format(e.as_ref(), false)
+                    ),
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if config_tables.is_empty() {
+        print_error(
+            cli_args.format,
+            "No synchronizer sections found; each section must have a `type` key.",
+        );
+        if !top_level_tables.is_empty() {
+            log_error!("Inspected tables: {}", top_level_tables.join(", "));
+        }
+        return ExitCode::FAILURE;
+    }
+    let mut report = RunReport {
+        run_id: global_config.run_id.clone(),
+        ..Default::default()
+    };
+    if let Some(only_type) = &cli_args.only_type {
+        for (_, t) in &config_tables {
+            let t_type = t.get("type").and_then(Value::as_str).unwrap_or_default();
+            if t_type != only_type {
+                report
+                    .skipped
+                    .push(format!("Section of type {} (not selected by --only)", t_type));
+            }
+        }
+        config_tables.retain(|(_, t)| t.get("type").and_then(Value::as_str) == Some(only_type.as_str()));
+    }
+    if !cli_args.exclude_types.is_empty() {
+        let filtered_sections = config_tables.len();
+        config_tables.retain(|(_, t)| {
+            let t_type = t.get("type").and_then(Value::as_str).unwrap_or_default();
+            if cli_args.exclude_types.iter().any(|e| e == t_type) {
+                report
+                    .skipped
+                    .push(format!("Section of type {} (excluded by --exclude-type)", t_type));
+                false
+            } else {
+                true
+            }
+        });
+        if filtered_sections > 0 && config_tables.is_empty() {
+            log_warn!("--exclude-type filtered out all remaining synchronizer sections.");
+        }
+    }
+    if config_tables.is_empty() {
+        print_error(cli_args.format, "No synchronizer sections of the selected type found.");
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = check_duplicate_types(&config_tables) {
+        print_error(
+            cli_args.format,
+            &format!("Error: {}", error_pretty_format(e.as_ref(), false)),
+        );
+        return ExitCode::FAILURE;
+    }
+
+    // Construct all synchronizers first, to report all broken sections at once
+    let mut synchronizers: Vec<(String, Box<dyn SystemConfigSynchronizer>)> = Vec::new();
+    let mut construction_failed = false;
+    for (section, table) in &config_tables {
+        let source = format!(
+            "section [{}] of {}",
+            section,
+            section_sources
+                .get(section)
+                .map(String::as_str)
+                .unwrap_or("unknown file")
+        );
+        let mut table = global_config.apply_defaults(table);
+        table
+            .entry("name".to_string())
+            .or_insert(Value::String(section.clone()));
+        match new_synchronizer(&table, global_config.query_env()) {
+            Ok(mut s) => {
+                s.set_no_remove(cli_args.no_remove);
+                if let Some(p) = s.as_any_mut().downcast_mut::<PackageSynchronizer>() {
+                    p.set_source(&source);
+                    p.set_allow_remove_sudo(cli_args.allow_remove_sudo);
+                    p.set_allow_empty_config(cli_args.allow_empty_config);
+                    p.set_allow_empty_groups(cli_args.allow_empty_groups);
+                    p.set_force(cli_args.force);
+                    p.set_query_jobs(global_config.query_jobs.unwrap_or_else(default_query_jobs));
+                }
+                if let Some(f) = s.as_any_mut().downcast_mut::<FlatpakSynchronizer>() {
+                    f.set_allow_empty_config(cli_args.allow_empty_config);
+                }
+                if let Some(c) = s.as_any_mut().downcast_mut::<CommandSynchronizer>() {
+                    c.set_allow_empty_config(cli_args.allow_empty_config);
+                    c.set_allow_empty_groups(cli_args.allow_empty_groups);
+                }
+                synchronizers.push((s.name().to_string(), s));
+            }
+            Err(e) => {
+                print_error(
+                    cli_args.format,
+                    &format!(
+                        "Error in config ({}): {}",
+                        source,
+                        error_pretty_format(e.as_ref(), false)
+                    ),
+                );
+                construction_failed = true;
+            }
+        }
+    }
+    if construction_failed {
+        return ExitCode::FAILURE;
+    }
+    // The pacman synchronizers, for the modes that only exist for pacman
+    let pacman_synchronizers: Vec<(&str, &PackageSynchronizer)> = synchronizers
+        .iter()
+        .filter_map(|(name, s)| s.as_any().downcast_ref().map(|p| (name.as_str(), p)))
+        .collect();
+    // Prints the section name before the output of each synchronizer, if there is more than one
+    let print_header = |name: &str| {
+        if synchronizers.len() > 1 {
+            println!("[{}]", name);
+        }
+    };
+
+    if let Some(groups) = &cli_args.list_groups_expansion {
+        for (name, pacman_config) in &pacman_synchronizers {
+            print_header(name);
+            if let Err(e) = print_groups_expansion(pacman_config, groups) {
+                log_error!(
+                    "Error running query commands: {}",
+                    error_pretty_format(e.as_ref(), false)
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.print_meta {
+        for (name, pacman_config) in &pacman_synchronizers {
+            print_header(name);
+            for l in pacman_config.describe_meta() {
+                println!("{}", l);
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.audit {
+        for (name, pacman_config) in &pacman_synchronizers {
+            print_header(name);
+            match pacman_config.get_untracked_explicit(cli_args.since_boot) {
+                Ok(untracked) => {
+                    println!(
+                        "{} explicitly installed packages are not in the config:",
+                        untracked.len()
+                    );
+                    for p in untracked {
+                        println!("    {}", p);
+                    }
+                }
+                Err(e) => {
+                    log_error!("Error running audit: {}", error_pretty_format(e.as_ref(), false));
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.print_provenance_tree {
+        println!("global config ({})", config_paths.join(", "));
+        if let Some(m) = global_config.manage_install_reason {
+            println!("  manage_install_reason = {}", m);
+        }
+        for (_, pacman_config) in &pacman_synchronizers {
+            match pacman_config.get_provenance_tree() {
+                Ok(lines) => {
+                    for l in lines {
+                        println!("  {}", l);
+                    }
+                }
+                Err(e) => {
+                    log_error!(
+                        "Error running query commands: {}",
+                        error_pretty_format(e.as_ref(), false)
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if let Some(iterations) = cli_args.benchmark {
+        for (name, synchronizer) in &synchronizers {
+            print_header(name);
+            if let Err(e) = benchmark_phases(synchronizer.as_ref(), iterations) {
+                log_error!(
+                    "Error running query commands: {}",
+                    error_pretty_format(e.as_ref(), false)
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.verify_reasons {
+        for (name, pacman_config) in &pacman_synchronizers {
+            print_header(name);
+            match pacman_config.get_install_reason_mismatches() {
+                Ok((should_be_explicit, should_be_dependency)) => {
+                    println!(
+                        "{} packages are installed as dependency, but are in the config:",
+                        should_be_explicit.len()
+                    );
+                    for p in should_be_explicit {
+                        println!("    {}", pacman_config.with_note(&p));
+                    }
+                    println!(
+                        "{} packages are installed explicitly, but are not in the config and required by others:",
+                        should_be_dependency.len()
+                    );
+                    for p in should_be_dependency {
+                        println!("    {}", p);
+                    }
+                }
+                Err(e) => {
+                    log_error!(
+                        "Error verifying install reasons: {}",
+                        error_pretty_format(e.as_ref(), false)
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.prune_config {
+        for (name, pacman_config) in &pacman_synchronizers {
+            print_header(name);
+            if let Err(e) = prune_config(pacman_config, &config_paths, cli_args.write) {
+                log_error!("Error pruning config: {}", error_pretty_format(e.as_ref(), false));
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.format == OutputFormat::Text {
+        for (name, synchronizer) in &synchronizers {
+            log_debug!("Config [{}]: {:?}", name, synchronizer);
+        }
+    }
+
+    if cli_args.verbose > 0 {
+        for (name, pacman_config) in &pacman_synchronizers {
+            match pacman_config.get_packages_also_in_groups() {
+                Ok(redundant) if !redundant.is_empty() => log_warn!(
+                    "Packages of [{}] are also in its groups and could be removed from packages: {}",
+                    name,
+                    redundant.join(", ")
+                ),
+                Ok(_) => (),
+                Err(e) => {
+                    log_error!(
+                        "Error running query commands: {}",
+                        error_pretty_format(e.as_ref(), false)
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+    if cli_args.verbose > 1 {
+        for (name, synchronizer) in &synchronizers {
+            let diff = match synchronizer.get_diff() {
+                Ok(diff) => diff,
+                Err(e) => {
+                    log_error!(
+                        "Error running query commands: {}",
+                        error_pretty_format(e.as_ref(), false)
+                    );
+                    return ExitCode::FAILURE;
+                }
+            };
+            let sets = [
+                ("to_install", &diff.to_install),
+                ("to_install_files", &diff.to_install_files),
+                ("to_remove", &diff.to_remove),
+                ("to_mark_explicit", &diff.to_mark_explicit),
+                ("to_mark_dependency", &diff.to_mark_dependency),
+            ];
+            for (set, packages) in sets {
+                log_debug!("[{}] {}: {}", name, set, packages.join(" "));
+            }
+        }
+    }
+
+    if cli_args.explain {
+        for (name, synchronizer) in &synchronizers {
+            print_header(name);
+            if let Err(e) = print_explanation(synchronizer.as_ref()) {
+                print_error(
+                    cli_args.format,
+                    &format!(
+                        "Error running query commands: {}",
+                        error_pretty_format(e.as_ref(), false)
+                    ),
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let named_synchronizers: Vec<(&str, &dyn SystemConfigSynchronizer)> = synchronizers
+        .iter()
+        .map(|(name, s)| (name.as_str(), s.as_ref()))
+        .collect();
+
+    for (_, pacman_config) in &pacman_synchronizers {
+        if let Err(e) = check_db_lock(
+            pacman_config,
+            cli_args.remove_stale_lock,
+            executes_commands(&cli_args, global_config.dry_mode),
+            &global_config.command_env(),
+        ) {
+            print_error(
+                cli_args.format,
+                &format!(
+                    "Error: pacman database is locked: {}",
+                    error_pretty_format(e.as_ref(), false)
+                ),
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut sync_reports: Vec<SynchronizerReport> = Vec::new();
+    // The synchronizers are constructed in the order of the config tables
+    for ((name, _), (_, table)) in named_synchronizers.iter().zip(&config_tables) {
+        sync_reports.push(SynchronizerReport {
+            name: name.to_string(),
+            sync_type: table
+                .get("type")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            ..Default::default()
+        });
+    }
+
+    // The status plans no phases, so its JSON only has the planned changes.
+    // The grouped preview has the same plan as the preview by synchronizer.
+    if cli_args.format == OutputFormat::Json {
+        let result = plan_phases(&named_synchronizers, &mut sync_reports, cli_args.mode).and_then(|_| {
+            for ((_, synchronizer), sync_report) in named_synchronizers.iter().zip(sync_reports.iter_mut()) {
+                sync_report.diff = Some(synchronizer.get_diff()?);
+            }
+            Ok(())
+        });
+        report.synchronizers.extend(sync_reports);
+        if let Err(e) = result {
+            print_error(
+                cli_args.format,
+                &format!(
+                    "Error running query commands: {}",
+                    error_pretty_format(e.as_ref(), false)
+                ),
+            );
+            return ExitCode::FAILURE;
+        }
+        if let Err(e) = check_change_count(&global_config, &report.synchronizers) {
+            print_error(
+                cli_args.format,
+                &format!(
+                    "Error checking planned changes: {}",
+                    error_pretty_format(e.as_ref(), false)
+                ),
+            );
+            return ExitCode::FAILURE;
+        }
+        println!("{}", report.to_json());
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.mode == RunMode::Status {
+        for (name, synchronizer) in &named_synchronizers {
+            print_header(name);
+            match synchronizer.get_planned_changes() {
+                Ok(changes) => {
+                    for (action, packages) in changes {
+                        println!("{} ({}): {}", action, packages.len(), packages.join(" "));
+                    }
+                }
+                Err(e) => {
+                    log_error!(
+                        "Error running query commands: {}",
+                        error_pretty_format(e.as_ref(), false)
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if cli_args.group_by_action {
+        if let Err(e) = print_grouped_by_action(&named_synchronizers, cli_args.preview_sort_by_source) {
+            log_error!(
                 "Error running query commands: {}",
                 error_pretty_format(e.as_ref(), false)
             );
             return ExitCode::FAILURE;
         }
-    };
-    println!("Down Commands:");
-    pretty_print_cmds(&down_cmds);
+        return ExitCode::SUCCESS;
+    }
 
-    let post_cmds = match pacman_config.get_post_cmds() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!(
+    let result = plan_phases(&named_synchronizers, &mut sync_reports, cli_args.mode);
+    // Checked before the preview, so that the warning points to the commands that follow
+    let change_count = result
+        .as_ref()
+        .ok()
+        .map(|_| check_change_count(&global_config, &sync_reports));
+    // The script shows the commands already
+    print_phases(&sync_reports, !cli_args.no_preview && cli_args.script.is_none());
+    if !cli_args.no_preview {
+        print_plan_notes(&pacman_synchronizers, &sync_reports);
+    }
+    report.synchronizers.extend(sync_reports);
+    let report_path = cli_args.report.as_deref();
+
+    if let Err(e) = result {
+        return fail_run(
+            &mut report,
+            report_path,
+            format!(
                 "Error running query commands: {}",
                 error_pretty_format(e.as_ref(), false)
+            ),
+        );
+    }
+    let change_count_exceeded = match change_count {
+        Some(Ok(exceeded)) => exceeded,
+        Some(Err(e)) => {
+            return fail_run(
+                &mut report,
+                report_path,
+                format!(
+                    "Error checking planned changes: {}",
+                    error_pretty_format(e.as_ref(), false)
+                ),
             );
-            return ExitCode::FAILURE;
         }
+        None => false,
     };
-    println!("Post Commands:");
-    pretty_print_cmds(&post_cmds);
 
-    ExitCode::SUCCESS
+    if cli_args.show_new_deps {
+        for (name, pacman_config) in &pacman_synchronizers {
+            match pacman_config.get_new_dependencies() {
+                Ok(deps) => {
+                    println!("{} new dependencies would be installed ({}):", deps.len(), name);
+                    for d in deps {
+                        println!("    {}", d);
+                    }
+                }
+                Err(e) => {
+                    return fail_run(
+                        &mut report,
+                        report_path,
+                        format!(
+                            "Error simulating the install: {}",
+                            error_pretty_format(e.as_ref(), false)
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    let plan_hash = report.plan_hash();
+    println!("Plan hash: {}", plan_hash);
+
+    if let Some(output_dir) = &cli_args.output_dir {
+        match report.write_plan_files(output_dir) {
+            Ok(paths) => {
+                for p in paths {
+                    log_info!("Plan written to {}", p.display());
+                }
+            }
+            Err(e) => {
+                return fail_run(
+                    &mut report,
+                    report_path,
+                    format!("Error writing plan files: {}", error_pretty_format(e.as_ref(), false)),
+                );
+            }
+        }
+    }
+
+    if let Some(expected) = &cli_args.expect_hash {
+        if *expected != plan_hash {
+            return fail_run(
+                &mut report,
+                report_path,
+                format!(
+                    "Error: Plan hash {} differs from the expected hash {}",
+                    plan_hash, expected
+                ),
+            );
+        }
+    }
+
+    if let Some(script) = &cli_args.script {
+        if let Err(e) = report.write_shell_script(script) {
+            return fail_run(
+                &mut report,
+                report_path,
+                format!("Error writing script: {}", error_pretty_format(e.as_ref(), false)),
+            );
+        }
+        log_info!("Script written to {}", script);
+        return finish_run(&report, report_path);
+    }
+
+    // Only the commands that are actually run need the space and the confirmation
+    if executes_commands(&cli_args, global_config.dry_mode) {
+        if change_count_exceeded {
+            if let Err(e) = confirm_change_count(cli_args.assume_yes) {
+                return fail_run(
+                    &mut report,
+                    report_path,
+                    format!("Error: {}", error_pretty_format(e.as_ref(), false)),
+                );
+            }
+        }
+        let cmds = match cli_args.run_command {
+            Some(index) => select_cmd(&report, index).into_iter().collect(),
+            None => report.cmds_in_run_order(),
+        };
+        if downloads_packages(&cmds) {
+            if let Err(e) = check_free_space(&global_config) {
+                return fail_run(
+                    &mut report,
+                    report_path,
+                    format!("Error checking free space: {}", error_pretty_format(e.as_ref(), false)),
+                );
+            }
+        }
+    }
+
+    let mut executed = Vec::new();
+    if let (Some(index), true) = (cli_args.run_command, global_config.dry_mode) {
+        // Like the whole plan, a single command only runs when applying
+        match select_cmd(&report, index) {
+            Ok((_, cmd)) => {
+                println!("Selected:");
+                pretty_print_cmds(std::slice::from_ref(cmd), index);
+                println!(
+                    "Not running it in dry mode. Set dry_mode = false in the global config or use --apply to run it."
+                );
+            }
+            Err(e) => {
+                return fail_run(
+                    &mut report,
+                    report_path,
+                    format!(
+                        "Error selecting command {}: {}",
+                        index,
+                        error_pretty_format(e.as_ref(), false)
+                    ),
+                );
+            }
+        }
+    } else if let Some(index) = cli_args.run_command {
+        let result = run_single_cmd(
+            &report,
+            index,
+            cli_args.confirm_each,
+            cli_args.assume_yes,
+            &global_config.command_env(),
+            &mut executed,
+        );
+        report.executed = executed;
+        if let Err(e) = result {
+            return fail_run(
+                &mut report,
+                report_path,
+                format!(
+                    "Error running command {}: {}",
+                    index,
+                    error_pretty_format(e.as_ref(), false)
+                ),
+            );
+        }
+    } else if !global_config.dry_mode {
+        log_info!("Running all commands:");
+        let result = apply_plan(
+            &report,
+            cli_args.confirm_each,
+            cli_args.assume_yes,
+            &global_config.command_env(),
+            &mut executed,
+        );
+        report.executed = executed;
+        if let Err(e) = &result {
+            report.errors.push(format!(
+                "Error applying the plan: {}",
+                error_pretty_format(e.as_ref(), false)
+            ));
+        }
+        if let Some(journal) = &global_config.journal_file {
+            if let Err(e) = report.append_to_journal(journal, false) {
+                log_error!("Error writing journal: {}", error_pretty_format(e.as_ref(), false));
+            }
+        }
+        if let Err(e) = result {
+            log_error!("Error applying the plan: {}", error_pretty_format(e.as_ref(), false));
+            return finish_run(&report, report_path);
+        }
+    } else if cli_args.journal_dry {
+        let journal = match &global_config.journal_file {
+            Some(j) => j,
+            None => {
+                return fail_run(
+                    &mut report,
+                    report_path,
+                    "Error: --journal-dry needs journal_file in the global config".to_string(),
+                );
+            }
+        };
+        if let Err(e) = report.append_to_journal(journal, true) {
+            return fail_run(
+                &mut report,
+                report_path,
+                format!("Error writing journal: {}", error_pretty_format(e.as_ref(), false)),
+            );
+        }
+    }
+
+    finish_run(&report, report_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::self_test::mock_runner;
+    use crate::test_util::{cmd, section, str_array, unique_temp_path};
+
+    use std::sync::{Arc, Mutex};
+
+    /// Serializes the tests that run commands, since they share the signal handlers and signal flags of the process.
+    static COMMAND_TESTS: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn fallback_backend_selection() {
+        let pacman = section("pacman", &[("packages", str_array(&["openssh"]))]);
+        let aur = section("aur", &[("helper_cmd", Value::String("yay".to_string()))]);
+        let apt = section(
+            "command",
+            &[
+                ("installed_cmd", Value::String("apt-mark showmanual".to_string())),
+                ("install_cmd", Value::String("sudo apt-get install -y".to_string())),
+                ("remove_cmd", Value::String("sudo apt-get remove -y".to_string())),
+            ],
+        );
+        let fallback = |backends: &[&Table]| {
+            let backends = backends.iter().map(|b| Value::Table((*b).clone())).collect();
+            section(
+                "fallback",
+                &[
+                    ("name", Value::String("tools".to_string())),
+                    ("backends", Value::Array(backends)),
+                ],
+            )
+        };
+        let select =
+            |backends: &[&Table], available: &[&str]| select_backend(fallback(backends), |b| available.contains(&b));
+        let selected_type = |t: AResult<Table>| t.unwrap()["type"].as_str().unwrap().to_string();
+
+        assert_eq!(
+            selected_type(select(&[&pacman, &aur, &apt], &["pacman", "yay", "apt-mark"])),
+            "pacman"
+        );
+        assert_eq!(
+            selected_type(select(&[&pacman, &aur, &apt], &["yay", "apt-mark"])),
+            "aur"
+        );
+        assert_eq!(
+            selected_type(select(&[&pacman, &aur, &apt], &["apt-mark", "sudo"])),
+            "command"
+        );
+        assert_eq!(select(&[&apt], &["apt-mark"]).unwrap()["name"].as_str(), Some("tools"));
+        assert_eq!(
+            select(&[&pacman, &apt], &["sudo"]).unwrap_err().to_string(),
+            "None of the backends is available"
+        );
+
+        // Alternatives of an unknown type are an error, even if an earlier one is selected
+        let unknown = section("apt", &[("packages", str_array(&["openssh-client"]))]);
+        assert!(select(&[&pacman, &unknown], &["pacman", "apt"]).is_err());
+    }
+
+    #[test]
+    fn change_count_only_counts_planned_phases() {
+        let config = section(
+            "command",
+            &[
+                ("packages", Value::Array(vec![Value::String("ruff".to_string())])),
+                ("installed_cmd", Value::String("pipx-list".to_string())),
+                ("install_cmd", Value::String("pipx install".to_string())),
+                ("remove_cmd", Value::String("pipx uninstall".to_string())),
+            ],
+        );
+        let mut s = new_command_with_runner(&config, Arc::new(mock_runner())).unwrap();
+        let global_config = GlobalConfig {
+            change_warn_threshold: Some(0),
+            change_hard_limit: Some(1),
+            ..Default::default()
+        };
+        let count = |s: &CommandSynchronizer, mode: RunMode| {
+            let mut sync_reports = vec![SynchronizerReport::default()];
+            plan_phases(&[("pipx", s)], &mut sync_reports, mode).unwrap();
+            check_change_count(&global_config, &sync_reports)
+        };
+
+        // Installs ruff and removes black and httpie
+        assert!(count(&s, RunMode::Sync).is_err());
+        assert!(count(&s, RunMode::Up).unwrap());
+        s.set_no_remove(true);
+        assert!(count(&s, RunMode::Sync).unwrap());
+    }
+
+    #[test]
+    fn explain_executes_nothing() {
+        let args = |a: &[&str]| parse_args(a.iter().map(|s| s.to_string())).unwrap();
+        assert!(!executes_commands(&args(&["explain"]), false));
+        assert!(!executes_commands(&args(&["--explain", "--run-command", "1"]), false));
+        assert!(executes_commands(&args(&[]), false));
+    }
+
+    #[test]
+    fn json_only_for_plan_and_status() {
+        let args = |a: &[&str]| parse_args(a.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(text_only_mode(&args(&["--format", "json"])), None);
+        assert_eq!(text_only_mode(&args(&["status", "--format", "json"])), None);
+        assert_eq!(text_only_mode(&args(&["--group-by", "action"])), None);
+        assert_eq!(text_only_mode(&args(&["audit", "--format", "json"])), Some("audit"));
+        assert_eq!(text_only_mode(&args(&["explain"])), Some("explain"));
+    }
+
+    #[test]
+    fn single_command_only_runs_when_applying() {
+        let args = |a: &[&str]| parse_args(a.iter().map(|s| s.to_string())).unwrap();
+        assert!(!executes_commands(&args(&["--run-command", "1"]), true));
+        assert!(executes_commands(&args(&["--run-command", "1"]), false));
+    }
+
+    #[test]
+    fn outcomes_of_executed_commands() {
+        let _lock = COMMAND_TESTS.lock().unwrap();
+        let cmds = [cmd(&["true"]), cmd(&["false"]), cmd(&["true"])];
+        let mut executed = Vec::new();
+        let result = run_cmds(
+            cmds.iter().map(|c| ("Up", c)),
+            false,
+            &CommandEnv::default(),
+            &mut executed,
+        );
+        assert!(result.is_err());
+        let outcomes: Vec<&CommandOutcome> = executed.iter().map(|e| &e.outcome).collect();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(*outcomes[0], CommandOutcome::Succeeded);
+        assert!(matches!(outcomes[1], CommandOutcome::Failed(_)));
+    }
+
+    #[test]
+    fn db_lock_only_matters_when_applying() {
+        let _lock = COMMAND_TESTS.lock().unwrap();
+        let lock = unique_temp_path("db.lck");
+        fs::write(&lock, "").unwrap();
+        let config = section(
+            "pacman",
+            &[
+                ("db_lock_file", Value::String(lock.to_string_lossy().to_string())),
+                ("sudo_cmd", Value::String(String::new())),
+            ],
+        );
+        let synchronizer = new_pacman_with_runner(&config, Arc::new(mock_runner())).unwrap();
+        if synchronizer.check_db_lock().unwrap() != DbLockState::Stale {
+            // pacman is running on this machine, so the lock is not stale
+            let _ = fs::remove_file(&lock);
+            return;
+        }
+
+        // Only applying runs can run into the lock
+        check_db_lock(&synchronizer, false, false, &CommandEnv::default()).unwrap();
+        assert!(check_db_lock(&synchronizer, false, true, &CommandEnv::default()).is_err());
+        check_db_lock(&synchronizer, true, false, &CommandEnv::default()).unwrap();
+        assert!(lock.exists());
+        check_db_lock(&synchronizer, true, true, &CommandEnv::default()).unwrap();
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn free_space_only_needed_for_downloads() {
+        let (update, remove) = (cmd(&["pacman", "-Syu"]), cmd(&["pacman", "-Rs", "x"]));
+        assert!(downloads_packages(&[("Pre", &update), ("Down", &remove)]));
+        assert!(!downloads_packages(&[("Down", &remove), ("Post", &remove)]));
+        assert!(!downloads_packages(&[]));
+    }
+
+    #[test]
+    fn sigquit_skips_rest_of_phase() {
+        let _lock = COMMAND_TESTS.lock().unwrap();
+        let quit = cmd(&["sh", "-c", "kill -QUIT $PPID"]);
+        let done = cmd(&["true"]);
+        let mut executed = Vec::new();
+        run_cmds(
+            [("Up", &quit), ("Up", &done), ("Down", &done)],
+            false,
+            &CommandEnv::default(),
+            &mut executed,
+        )
+        .unwrap();
+        let outcomes: Vec<(&str, &CommandOutcome)> = executed.iter().map(|e| (e.phase.as_str(), &e.outcome)).collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("Up", &CommandOutcome::Succeeded),
+                ("Up", &CommandOutcome::Skipped),
+                ("Down", &CommandOutcome::Succeeded),
+            ]
+        );
+    }
+
+    #[test]
+    fn sigint_aborts_between_commands() {
+        let _lock = COMMAND_TESTS.lock().unwrap();
+        let interrupt = cmd(&["sh", "-c", "kill -INT $PPID"]);
+        let done = cmd(&["true"]);
+        let mut executed = Vec::new();
+        let e = run_cmds(
+            [("Up", &interrupt), ("Up", &done)],
+            false,
+            &CommandEnv::default(),
+            &mut executed,
+        )
+        .unwrap_err();
+        assert!(e.to_string().starts_with("Interrupted"));
+        assert_eq!(executed.len(), 1);
+    }
+
+    #[test]
+    fn synchronizer_types() {
+        let mut non_string_type = Table::new();
+        non_string_type.insert("type".to_string(), Value::Integer(42));
+        assert!(get_synchronizer_type(&non_string_type).is_err());
+        assert!(new_synchronizer(&section("foo", &[]), CommandEnv::default()).is_err());
+    }
+
+    #[test]
+    fn duplicate_sections() {
+        let flatpak = section("flatpak", &[]);
+        let allowed = section("flatpak", &[("allow_multiple", Value::Boolean(true))]);
+        assert!(check_duplicate_types(&[
+            ("user".to_string(), flatpak.clone()),
+            ("system".to_string(), flatpak.clone()),
+        ])
+        .is_err());
+        assert!(check_duplicate_types(&[("user".to_string(), flatpak), ("system".to_string(), allowed)]).is_ok());
+    }
+
+    #[test]
+    fn include_merge() {
+        let mut global = Table::new();
+        global.insert("dry_mode".to_string(), Value::Boolean(true));
+        let tools = |name: &str| Value::Table(Table::from_iter([(name.to_string(), Value::Table(section(name, &[])))]));
+
+        let mut main_file = Table::new();
+        main_file.insert("global".to_string(), Value::Table(global.clone()));
+        main_file.insert("tools".to_string(), tools("pacman"));
+        let mut included_file = Table::new();
+        included_file.insert("tools".to_string(), tools("flatpak"));
+        let mut overriding_file = included_file.clone();
+        overriding_file.insert("global".to_string(), Value::Table(global));
+
+        let mut config = main_file.clone();
+        merge_config_tables(&mut config, included_file, "", false).unwrap();
+        match config.get("tools") {
+            Some(Value::Table(tools)) => assert_eq!(tools.keys().collect::<Vec<_>>(), ["flatpak", "pacman"]),
+            other => panic!("tools is not a table: {:?}", other),
+        }
+
+        assert!(merge_config_tables(&mut main_file, overriding_file, "", false).is_err());
+    }
+
+    #[test]
+    fn circular_include() {
+        let dir = unique_temp_path("include");
+        let a = dir.join("a.toml");
+        let b = dir.join("b.toml");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+
+        let mut chain = vec![check_include_cycle(&[], &a.to_string_lossy()).unwrap()];
+        chain.push(check_include_cycle(&chain, &b.to_string_lossy()).unwrap());
+        let result = check_include_cycle(&chain, &a.to_string_lossy());
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(result.unwrap_err().to_string().starts_with("Circular include:"));
+    }
+
+    #[test]
+    fn missing_config_file() {
+        let path = unique_temp_path("missing.toml");
+        let e = load_config_file(
+            &path.to_string_lossy(),
+            &mut Vec::new(),
+            &mut Table::new(),
+            &mut BTreeMap::new(),
+            true,
+        )
+        .unwrap_err();
+        assert!(e.to_string().starts_with("Error reading config file"));
+    }
+
+    #[test]
+    fn second_instance_is_locked_out() {
+        let path = unique_temp_path("instance.lock");
+        let path = path.to_string_lossy();
+        let first = acquire_instance_lock(&path);
+        let second = acquire_instance_lock(&path);
+        let _ = fs::remove_file(path.as_ref());
+
+        assert!(first.is_ok());
+        assert!(second.unwrap_err().to_string().starts_with("Another instance"));
+    }
 }
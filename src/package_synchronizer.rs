@@ -1,35 +1,270 @@
-use crate::{AResult, CommandVector};
+use crate::config_schema::KeyType;
+use crate::global_config::CommandEnv;
+use crate::logging::{log_debug, log_enabled, log_warn, LogLevel};
+use crate::{get_from_table, spawn_error, AResult, CommandError, CommandVector};
 
+use std::any::Any;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
-use std::io::{self, BufRead};
-use std::process::{Command, Stdio};
-use toml::de::Error;
-use toml::{Table, Value};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn get_packages_from_command<T: AsRef<OsStr>>(cmd: &[T]) -> AResult<Vec<String>> {
+/// What to do with lines of query output that are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonUtf8Policy {
+    /// Replace invalid bytes with U+FFFD and warn.
+    #[default]
+    Lossy,
+    /// Drop the line and warn.
+    Skip,
+}
+
+fn toml_value_to_non_utf8_policy(val: Option<&toml::Value>) -> AResult<NonUtf8Policy> {
+    match val.map(|v| v.as_str()) {
+        None => Ok(NonUtf8Policy::default()),
+        Some(Some("lossy")) => Ok(NonUtf8Policy::Lossy),
+        Some(Some("skip")) => Ok(NonUtf8Policy::Skip),
+        Some(_) => Err("non_utf8_names must be either \"lossy\" or \"skip\"".into()),
+    }
+}
+
+/// Splits the output of a query command into lines, handling lines that are not valid UTF-8 according to the policy.
+pub fn split_query_output(output: &[u8], policy: NonUtf8Policy) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in output.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match (std::str::from_utf8(line), policy) {
+            (Ok(l), _) => lines.push(l.to_string()),
+            (Err(_), NonUtf8Policy::Lossy) => {
+                let l = String::from_utf8_lossy(line).into_owned();
+                log_warn!("Query output is not valid UTF-8, using {}", l);
+                lines.push(l);
+            }
+            (Err(_), NonUtf8Policy::Skip) => {
+                log_warn!(
+                    "Skipping query output that is not valid UTF-8: {}",
+                    String::from_utf8_lossy(line)
+                );
+            }
+        }
+    }
+    // The final newline does not start another line
+    if output.ends_with(b"\n") || output.is_empty() {
+        lines.pop();
+    }
+    lines
+}
+
+/// Runs a query command and returns the lines of its output.
+/// Exit codes in `allow_exit_codes` are treated as success, for commands that signal "nothing found" by an exit code.
+fn get_packages_from_command<T: AsRef<OsStr>>(
+    cmd: &[T],
+    env: &CommandEnv,
+    non_utf8: NonUtf8Policy,
+    allow_exit_codes: &[i32],
+) -> AResult<Vec<String>> {
     if cmd.is_empty() {
         return Ok(Vec::new());
     }
 
-    let mut cmd_proc = Command::new(&cmd[0])
+    let mut cmd_proc = Command::new(&cmd[0]);
+    env.apply(&mut cmd_proc);
+    cmd_proc
         .args(&cmd[1..])
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()?;
-    if !cmd_proc.wait().expect("Command should be spawned!").success() {
-        return Err(Box::from("Command did not succeed"));
-    }
-    let mut package_list: Vec<String> =
-        io::BufReader::new(cmd_proc.stdout.take().expect("Stdout should be available!"))
-            .lines()
-            .map_while(Result::ok)
-            .collect();
-    package_list.sort_unstable(); // TODO MAYBE: replace by cleanup_package_list (commands should generally not return duplicates, so this may be unnecessary) or remove
-    Ok(package_list)
+        .stderr(Stdio::piped());
+    let cmd_out = match env.timeout {
+        Some(timeout) => output_with_timeout(&mut cmd_proc, timeout),
+        None => cmd_proc.output(),
+    }
+    .map_err(|e| spawn_error(&cmd[0].as_ref().to_string_lossy(), e))?;
+    let allowed_exit_code = cmd_out.status.code().is_some_and(|c| allow_exit_codes.contains(&c));
+    if !cmd_out.status.success() && !allowed_exit_code {
+        let cmd_str: Vec<String> = cmd.iter().map(|c| c.as_ref().to_string_lossy().into_owned()).collect();
+        return Err(Box::new(CommandError::new(
+            &cmd_str,
+            &String::from_utf8_lossy(&cmd_out.stderr),
+        )));
+    }
+    // Still show warnings of successful commands, like before.
+    io::stderr().write_all(&cmd_out.stderr)?;
+    let lines = split_query_output(&cmd_out.stdout, non_utf8);
+    if log_enabled(LogLevel::Debug) {
+        let cmd_str: Vec<String> = cmd.iter().map(|c| c.as_ref().to_string_lossy().into_owned()).collect();
+        log_debug!("Query: {} ({} lines)", cmd_str.join(" "), lines.len());
+    }
+    Ok(lines)
+}
+
+/// Reads a pipe of a child process to the end in its own thread.
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf)?;
+        }
+        Ok(buf)
+    })
+}
+
+/// Like `Command::output`, but kills the command if it does not finish within `timeout`.
+fn output_with_timeout(cmd: &mut Command, timeout: Duration) -> io::Result<Output> {
+    let start = Instant::now();
+    let mut child = cmd.spawn()?;
+    // Read the pipes while waiting, otherwise a full pipe would block the command
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Command timed out after {} seconds", timeout.as_secs_f32()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let join = |h: thread::JoinHandle<io::Result<Vec<u8>>>| {
+        h.join()
+            .unwrap_or_else(|_| Err(io::Error::other("Reading the command output panicked")))
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Reads a newline-delimited list of items from a file, as an alternative to a query command.
+/// Empty lines are ignored.
+pub fn get_packages_from_file(path: &str) -> AResult<Vec<String>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Parses the effective user id from the content of `/proc/<pid>/status`.
+pub fn parse_effective_uid(status: &str) -> Option<u32> {
+    // Format: "Uid:\t<real>\t<effective>\t<saved>\t<filesystem>"
+    let uid_line = status.lines().find(|l| l.starts_with("Uid:"))?;
+    uid_line.split_whitespace().nth(2)?.parse().ok()
 }
 
-fn compare_lists_only_in_first(l1: &[String], l2: &[String]) -> Vec<String> {
+/// Checks if the current process runs with root privileges (EUID 0).
+/// AUR helpers refuse to build packages as root, so the AUR synchronizer rejects running as root.
+pub fn is_root() -> AResult<bool> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let uid = parse_effective_uid(&status).ok_or("Could not find effective user id in /proc/self/status")?;
+    Ok(uid == 0)
+}
+
+/// Converts a timestamp of the pacman log (like `2023-08-01T12:34:56+0200`) into seconds since the Unix epoch.
+/// Returns `None` for other formats, like the old format without seconds and time zone.
+fn parse_pacman_log_timestamp(ts: &str) -> Option<i64> {
+    let num = |r: std::ops::Range<usize>| ts.get(r)?.parse::<i64>().ok();
+    if ts.len() != 24 || &ts[4..5] != "-" || &ts[7..8] != "-" || &ts[10..11] != "T" {
+        return None;
+    }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    let offset_sign = match &ts[19..20] {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let offset = offset_sign * (num(20..22)? * 3600 + num(22..24)? * 60);
+
+    // Days since the epoch of the civil date (see http://howardhinnant.github.io/date_algorithms.html)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// Returns the packages that the pacman log reports as installed at or after the given time (seconds since epoch).
+pub fn packages_installed_since(log: &str, since: i64) -> Vec<String> {
+    let mut packages: Vec<String> = log
+        .lines()
+        .filter_map(|l| {
+            let (ts, rest) = l.strip_prefix('[')?.split_once("] [ALPM] installed ")?;
+            let package = rest.split_whitespace().next()?;
+            (parse_pacman_log_timestamp(ts)? >= since).then(|| package.to_string())
+        })
+        .collect();
+    cleanup_package_list(&mut packages);
+    packages
+}
+
+/// Reads the boot time in seconds since epoch from `/proc/stat`.
+fn get_boot_time() -> AResult<i64> {
+    let stat = fs::read_to_string("/proc/stat")?;
+    let btime = stat
+        .lines()
+        .find_map(|l| l.strip_prefix("btime "))
+        .ok_or("Could not find boot time in /proc/stat")?;
+    Ok(btime.trim().parse()?)
+}
+
+/// Removes ANSI escape sequences (like colors) from a string.
+fn strip_ansi_escapes(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        // CSI sequences end with a byte in the range @ to ~.
+        // Other escapes have optional intermediate bytes (space to /) followed by a single final byte.
+        if chars.next_if_eq(&'[').is_some() {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else {
+            while chars.next_if(|c| (' '..='/').contains(c)).is_some() {}
+            chars.next();
+        }
+    }
+    out
+}
+
+/// Post processing for query commands that do not produce clean line-oriented output.
+/// Strips ANSI escape sequences, trims every line and drops empty lines.
+pub fn clean_query_output(lines: Vec<String>) -> Vec<String> {
+    lines
+        .iter()
+        .map(|l| strip_ansi_escapes(l).trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// All list comparisons use `binary_search` and thus expect the second list to be sorted in byte order,
+/// as done by `cleanup_package_list`. The order of the command output (which may be locale dependent) is never used.
+pub fn compare_lists_only_in_first(l1: &[String], l2: &[String]) -> Vec<String> {
+    debug_assert!(l2.is_sorted(), "List comparison needs a sorted list");
     l1.iter()
         .filter(|item| l2.binary_search(item).is_err())
         .cloned()
@@ -37,21 +272,52 @@ fn compare_lists_only_in_first(l1: &[String], l2: &[String]) -> Vec<String> {
 }
 
 fn compare_lists_in_both(l1: &[String], l2: &[String]) -> Vec<String> {
+    debug_assert!(l2.is_sorted(), "List comparison needs a sorted list");
     l1.iter()
         .filter(|item| l2.binary_search(item).is_ok())
         .cloned()
         .collect()
 }
 
+/// Checks if a blacklist entry is a glob pattern instead of a package name.
+fn is_glob_pattern(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Matches a name against a glob pattern, where `*` matches any sequence of characters and `?` a single character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Pattern position after the last `*`, and the name position where its match ends so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` match one more character
+            p = star_p;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 /// Function that does all the post processing of a package list.
 /// Mainly sorting the vector and detecting and removing duplicates.
-fn cleanup_package_list<T: PartialEq + Ord>(l: &mut Vec<T>) {
+pub fn cleanup_package_list<T: PartialEq + Ord>(l: &mut Vec<T>) {
     l.sort_unstable();
     l.dedup();
 }
 
-#[allow(unused)]
-fn toml_value_to_cmd_array(val: &toml::Value) -> AResult<CommandVector> {
+pub fn toml_value_to_cmd_array(key: &str, val: &toml::Value) -> AResult<CommandVector> {
     match val {
         toml::Value::String(s) => Ok(s.split_whitespace().map(String::from).collect()),
         toml::Value::Array(arr) => {
@@ -59,19 +325,94 @@ fn toml_value_to_cmd_array(val: &toml::Value) -> AResult<CommandVector> {
             for v in arr {
                 match v {
                     toml::Value::String(s) => str_arr.push(s.clone()),
-                    _ => return Err("Array contains non-String Elements.".into()),
+                    _ => return Err(format!("{}: Array contains non-String Elements.", key).into()),
                 }
             }
             Ok(str_arr)
         }
-        _ => Err("Value is not String or Array!".into()),
+        _ => Err(format!("{}: Value is not String or Array!", key).into()),
+    }
+}
+
+/// Checks an overridden command.
+/// Commands must not be empty, and critical (modifying) commands must contain
+/// the invocation of their package manager followed by at least its operation.
+/// `manager` is only given for critical commands.
+pub fn validate_cmd_override(key: &str, cmd: &[String], manager: Option<&str>) -> AResult<()> {
+    if cmd.is_empty() {
+        return Err(format!("{}: Command is empty.", key).into());
+    }
+    if let Some(manager) = manager {
+        let manager_pos = cmd
+            .iter()
+            .position(|c| Path::new(c).file_name() == Some(OsStr::new(manager)));
+        match manager_pos {
+            Some(pos) if pos + 1 < cmd.len() => (),
+            Some(_) => return Err(format!("{}: {} is missing its operation (like -S).", key, manager).into()),
+            None => return Err(format!("{}: Command does not invoke {}.", key, manager).into()),
+        }
     }
+    Ok(())
 }
 
-fn get_from_table<'a, T: toml::macros::Deserialize<'a>>(table: &Table, key: &str, default: T) -> Result<T, Error> {
-    table
-        .get(key)
-        .map_or(Ok(default), |v: &Value| Value::try_into::<T>(v.clone()))
+/// Package names, notes and repositories of a package list.
+type PackageList = (Vec<String>, BTreeMap<String, String>, BTreeMap<String, String>);
+
+/// Reads the package list. Entries can be package names or tables with a name and a note.
+/// Names can be prefixed with a repository (`extra/firefox`). The repository is split off, so that the
+/// bare name can be compared to the query results, and is returned separately, mapped by the bare name.
+fn toml_value_to_package_list(val: Option<&toml::Value>) -> AResult<PackageList> {
+    let mut packages = Vec::new();
+    let mut notes = BTreeMap::new();
+    let mut repos = BTreeMap::new();
+
+    let arr = match val {
+        None => return Ok((packages, notes, repos)),
+        Some(toml::Value::Array(arr)) => arr,
+        Some(_) => return Err("packages is not an Array!".into()),
+    };
+
+    for v in arr {
+        let name = match v {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Table(t) => {
+                for k in t.keys() {
+                    if !["name", "note"].contains(&k.as_str()) {
+                        return Err(format!("Unknown key in package entry: {}", k).into());
+                    }
+                }
+                let name = match t.get("name") {
+                    Some(toml::Value::String(n)) => n.clone(),
+                    _ => return Err("Package entry has no name or name is not a String.".into()),
+                };
+                match t.get("note") {
+                    Some(toml::Value::String(n)) => {
+                        notes.insert(name.clone(), n.clone());
+                    }
+                    Some(_) => return Err(format!("Note of package {} is not a String.", name).into()),
+                    None => (),
+                }
+                name
+            }
+            _ => return Err("packages contains Elements that are neither String nor Table.".into()),
+        };
+
+        match name.split_once('/') {
+            Some((repo, bare_name)) => {
+                if repo.is_empty() || bare_name.is_empty() {
+                    return Err(format!("Invalid repository qualified package: {}", name).into());
+                }
+                if let Some(note) = notes.remove(&name) {
+                    notes.insert(bare_name.to_string(), note);
+                }
+                repos.insert(bare_name.to_string(), repo.to_string());
+                packages.push(bare_name.to_string());
+            }
+            None => packages.push(name),
+        }
+    }
+
+    Ok((packages, notes, repos))
 }
 
 /// Single Ok.
@@ -88,19 +429,268 @@ fn concat<T: Clone>(l1: &[T], l2: &[T]) -> Vec<T> {
     [l1, l2].concat()
 }
 
-pub trait SystemConfigSynchronizer {
+/// Runs the query commands of a synchronizer.
+/// Can be replaced to run the synchronizer against synthetic data.
+pub trait QueryRunner: fmt::Debug + Send + Sync {
+    fn get_packages(&self, cmd: &[String]) -> AResult<Vec<String>>;
+
+    /// Whether the synchronizer runs as root, so that the modifying commands do not need sudo.
+    fn is_root(&self) -> AResult<bool> {
+        Ok(false)
+    }
+}
+
+/// Query runner that actually executes the commands on the system.
+#[derive(Debug, Clone, Default)]
+pub struct SystemQueryRunner {
+    pub env: CommandEnv,
+    pub non_utf8: NonUtf8Policy,
+    pub allow_exit_codes: Vec<i32>,
+}
+
+impl QueryRunner for SystemQueryRunner {
+    fn get_packages(&self, cmd: &[String]) -> AResult<Vec<String>> {
+        get_packages_from_command(cmd, &self.env, self.non_utf8, &self.allow_exit_codes)
+    }
+
+    fn is_root(&self) -> AResult<bool> {
+        is_root()
+    }
+}
+
+/// Results of the query commands, so that each command runs only once per invocation.
+/// All queries run before the system is changed, so the results cannot go stale.
+/// Clones share the results.
+#[derive(Clone, Default)]
+struct QueryCache(Arc<Mutex<BTreeMap<CommandVector, Vec<String>>>>);
+
+impl QueryCache {
+    fn get(&self, cmd: &[String]) -> Option<Vec<String>> {
+        self.0.lock().unwrap().get(cmd).cloned()
+    }
+
+    fn insert(&self, cmd: &[String], packages: &[String]) {
+        self.0.lock().unwrap().insert(cmd.to_vec(), packages.to_vec());
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+impl fmt::Debug for QueryCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryCache({} results)", self.0.lock().unwrap().len())
+    }
+}
+
+/// Counting semaphore that limits how many query commands run at the same time.
+#[derive(Debug)]
+struct JobLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl JobLimiter {
+    fn new(jobs: usize) -> Self {
+        JobLimiter {
+            available: Mutex::new(jobs.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Runs `f` as soon as one of the jobs is available.
+    fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        {
+            let mut available = self.available.lock().unwrap();
+            while *available == 0 {
+                available = self.released.wait(available).unwrap();
+            }
+            *available -= 1;
+        }
+        let result = f();
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+        result
+    }
+}
+
+/// Default for the number of parallel query commands: the number of CPUs.
+pub fn default_query_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// State of the pacman database lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbLockState {
+    Free,
+    /// The lock exists and a pacman process is running.
+    Held,
+    /// The lock exists, but no pacman process is running (e.g. after a crash).
+    Stale,
+}
+
+/// Checks if a process with the given name is running, by looking through `/proc`.
+fn is_process_running(name: &str) -> AResult<bool> {
+    for entry in fs::read_dir("/proc")? {
+        let path = entry?.path();
+        // Processes may exit while we look at them, so ignore unreadable entries
+        if let Ok(comm) = fs::read_to_string(path.join("comm")) {
+            if comm.trim_end() == name {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Difference between the config and the system, as package lists instead of commands.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncDiff {
+    pub to_install: Vec<String>,
+    /// Paths of package files to install.
+    pub to_install_files: Vec<String>,
+    pub to_remove: Vec<String>,
+    pub to_mark_explicit: Vec<String>,
+    pub to_mark_dependency: Vec<String>,
+}
+
+/// Commands of all phases of a synchronizer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    pub pre: Vec<CommandVector>,
+    pub up: Vec<CommandVector>,
+    pub down: Vec<CommandVector>,
+    pub post: Vec<CommandVector>,
+    /// Number of packages that are touched by the up commands.
+    pub up_changes: usize,
+    /// Number of packages that are touched by the down commands.
+    pub down_changes: usize,
+}
+
+impl Plan {
+    /// The phases with their names, in the order they run.
+    pub fn into_phases(self) -> [(&'static str, Vec<CommandVector>); 4] {
+        [
+            ("Pre", self.pre),
+            ("Up", self.up),
+            ("Down", self.down),
+            ("Post", self.post),
+        ]
+    }
+}
+
+pub trait SystemConfigSynchronizer: fmt::Debug {
+    /// Access to the concrete synchronizer, for features that only exist for one backend.
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Name of the synchronizer in the output. The `name` key of the config, which defaults to the section path.
+    fn name(&self) -> &str;
+    /// Only add: never plan removals, marking as dependency or the orphan cleanup. Set from the command line.
+    fn set_no_remove(&mut self, no_remove: bool);
     fn get_pre_cmds(&self) -> AResult<Vec<CommandVector>>;
     fn get_post_cmds(&self) -> AResult<Vec<CommandVector>>;
     fn get_up_cmds(&self) -> AResult<Vec<CommandVector>>;
     fn get_down_cmds(&self) -> AResult<Vec<CommandVector>>;
+    /// Commands of the given phases ("Pre", "Up", "Down" or "Post"). The other phases stay empty,
+    /// so that e.g. a failing removal check does not prevent an install-only run.
+    /// The pacman synchronizer caches its queries, so all phases are planned from the same system state.
+    fn plan(&self, phases: &[&str]) -> AResult<Plan> {
+        let phase = |name: &str, get_cmds: &dyn Fn() -> AResult<Vec<CommandVector>>| {
+            if phases.contains(&name) {
+                get_cmds()
+            } else {
+                Ok(Vec::new())
+            }
+        };
+        let count = |name: &str, count_changes: &dyn Fn() -> AResult<usize>| {
+            if phases.contains(&name) {
+                count_changes()
+            } else {
+                Ok(0)
+            }
+        };
+        Ok(Plan {
+            pre: phase("Pre", &|| self.get_pre_cmds())?,
+            up: phase("Up", &|| self.get_up_cmds())?,
+            down: phase("Down", &|| self.get_down_cmds())?,
+            post: phase("Post", &|| self.get_post_cmds())?,
+            up_changes: count("Up", &|| self.count_up_changes())?,
+            down_changes: count("Down", &|| self.count_down_changes())?,
+        })
+    }
+    /// Number of packages that are touched by the up commands.
+    fn count_up_changes(&self) -> AResult<usize>;
+    /// Number of packages that are touched by the down commands.
+    fn count_down_changes(&self) -> AResult<usize>;
+    /// Packages that the up and down commands would change.
+    fn get_diff(&self) -> AResult<SyncDiff>;
+    /// Planned changes, categorized by action.
+    /// Returns a list of actions (like "Install") with the affected packages.
+    fn get_planned_changes(&self) -> AResult<Vec<(String, Vec<String>)>>;
+    /// Detailed trace of how the commands are computed.
+    /// Returns a list of named sections with the lines of each section.
+    fn get_explanation(&self) -> AResult<Vec<(String, Vec<String>)>>;
 }
 
 #[derive(Debug, Clone)]
 pub struct PackageSynchronizer {
+    name: String,
     packages: Vec<String>,
     groups: Vec<String>,
+    /// Packages that are never installed, even if a group contains them.
     blacklist: Vec<String>,
+    /// Blacklist entries with `*` or `?`, matched against each package of the config state.
+    /// Only the blacklist supports patterns, `packages` and `groups` are always exact names.
+    blacklist_patterns: Vec<String>,
+    /// Paths of package files that are not in any repository. They are installed if no package
+    /// with the same name is installed. Newer versions of a file are not detected, since only the name is compared.
+    local_packages: Vec<String>,
+    /// Packages that are kept installed, but never upgraded. They are ignored by the update command.
+    hold: Vec<String>,
+    /// Packages that are not upgraded, but otherwise handled like all other packages. Unlike `hold`, they are not kept installed.
+    ignore: Vec<String>,
+    /// Packages that are never removed, even if they are not in the config. Unlike `blacklist`, this is about removing.
+    protected: Vec<String>,
+    /// Refuse to remove more packages than this, since that is most likely a broken config.
+    max_remove: usize,
+    /// Refuse to remove more than this percentage of the installed packages.
+    max_remove_percent: Option<usize>,
+    /// Optional notes for packages (who added them and why). Purely informational.
+    notes: BTreeMap<String, String>,
+    /// Repositories to install packages from, if given as `repo/package`. Maps the bare name to the repository.
+    repos: BTreeMap<String, String>,
+    /// Renamed packages. Maps the old name to the new name.
+    replaces: BTreeMap<String, String>,
+    /// Mark packages as explicit or dependency to match the config.
+    /// If disabled, packages are only installed and removed. The orphan cleanup relies on the install reasons:
+    /// packages that are not in the config, but still required by others, stay explicit and are only removed
+    /// in a later run, once nothing requires them anymore. Config packages that are installed as dependency
+    /// are exempted from the orphan cleanup.
+    manage_install_reason: bool,
+    /// Report pending .pacnew and .pacsave files after the upgrade.
+    handle_pacnew: bool,
+    /// Install without `--needed`, which reinstalls up to date packages.
+    reinstall: bool,
+    /// Refuse to run while the pacman database is locked.
+    check_db_lock: bool,
+    /// Allow removing the package that provides `sudo_cmd`. Set from the command line.
+    allow_remove_sudo: bool,
+    /// Allow removals even if the config state is empty. Set from the command line.
+    allow_empty_config: bool,
+    /// Only warn about groups without packages, instead of failing. Set from the command line.
+    allow_empty_groups: bool,
+    /// Ignore `max_remove` and `max_remove_percent`. Set from the command line.
+    force: bool,
+    /// Only install and mark as explicit, never mark as dependency or remove. Set from the command line.
+    no_remove: bool,
+    /// Maximum number of query commands that run at the same time. Set from the global config.
+    query_jobs: usize,
+    /// Config file and section this synchronizer was read from, for error messages.
+    source: String,
     meta: PackageSynchronizerMeta,
+    runner: Arc<dyn QueryRunner>,
+    query_cache: QueryCache,
 }
 
 #[derive(Debug, Clone)]
@@ -116,95 +706,995 @@ struct PackageSynchronizerMeta {
     update_cmd: CommandVector,
     get_orphans_cmd: CommandVector,
     get_group_packages_cmd: CommandVector,
+    kernel_release_cmd: CommandVector,
+    file_owner_cmd: CommandVector,
+    pacnew_cmd: CommandVector,
+    dependency_tree_cmd: CommandVector,
+    log_file: String,
+    /// Prefix of the modifying commands. Empty to run them directly, e.g. as root.
+    sudo_cmd: CommandVector,
+    db_lock_file: String,
+    remove_lock_cmd: CommandVector,
+    local_package_name_cmd: CommandVector,
+    install_file_cmd: CommandVector,
+    /// Prints the names of all packages an install would download, including new dependencies.
+    simulate_install_cmd: CommandVector,
+    /// Clean up the output of query commands with `clean_query_output`.
+    clean_query_output: bool,
 }
 
-pub fn new_pacman(config: &toml::Table) -> AResult<PackageSynchronizer> {
-    let allowed_keys = ["type", "sudo_cmd", "packages", "groups", "blacklist"];
+impl PackageSynchronizerMeta {
+    /// Readable description of all command templates and settings, one `key = value` line each.
+    fn describe(&self) -> Vec<String> {
+        let cmds = [
+            ("installed_packages_cmd", &self.installed_packages_cmd),
+            ("dependency_packages_cmd", &self.dependency_packages_cmd),
+            ("explicitly_installed_cmd", &self.explicitly_installed_cmd),
+            ("explicitly_unrequired_cmd", &self.explicitly_unrequired_cmd),
+            ("as_explicit_cmd", &self.as_explicit_cmd),
+            ("install_cmd", &self.install_cmd),
+            ("as_dependency_cmd", &self.as_dependency_cmd),
+            ("remove_cmd", &self.remove_cmd),
+            ("update_cmd", &self.update_cmd),
+            ("get_orphans_cmd", &self.get_orphans_cmd),
+            ("get_group_packages_cmd", &self.get_group_packages_cmd),
+            ("kernel_release_cmd", &self.kernel_release_cmd),
+            ("file_owner_cmd", &self.file_owner_cmd),
+            ("pacnew_cmd", &self.pacnew_cmd),
+            ("dependency_tree_cmd", &self.dependency_tree_cmd),
+            ("remove_lock_cmd", &self.remove_lock_cmd),
+            ("local_package_name_cmd", &self.local_package_name_cmd),
+            ("install_file_cmd", &self.install_file_cmd),
+            ("simulate_install_cmd", &self.simulate_install_cmd),
+        ];
+
+        let mut lines: Vec<String> = cmds.iter().map(|(key, cmd)| format!("{} = {:?}", key, cmd)).collect();
+        lines.push(format!("sudo_cmd = {:?}", self.sudo_cmd));
+        lines.push(format!("log_file = {:?}", self.log_file));
+        lines.push(format!("db_lock_file = {:?}", self.db_lock_file));
+        lines.push(format!("clean_query_output = {}", self.clean_query_output));
+        lines
+    }
+}
+
+/// Packages that are protected from removal if the config does not set `protected`.
+/// Without them, the system would not boot or could not be repaired with pacman.
+pub const DEFAULT_PROTECTED: [&str; 4] = ["base", "linux", "linux-firmware", "pacman"];
+
+/// Maximum width of the package lines of a generated config, without the indentation.
+const GENERATED_LINE_WIDTH: usize = 96;
+
+/// Default of `max_remove`.
+pub const DEFAULT_MAX_REMOVE: usize = 50;
+
+/// All keys of a pacman config section, with their types.
+/// Used to check for unknown keys and to generate the config schema.
+pub const PACMAN_KEYS: [(&str, KeyType); 40] = [
+    ("type", KeyType::String),
+    ("name", KeyType::String),
+    ("allow_multiple", KeyType::Bool),
+    ("sudo_cmd", KeyType::Command),
+    ("pacman_cmd", KeyType::String),
+    ("packages", KeyType::PackageList),
+    ("groups", KeyType::StringList),
+    ("blacklist", KeyType::StringList),
+    ("local_packages", KeyType::StringList),
+    ("hold", KeyType::StringList),
+    ("ignore", KeyType::StringList),
+    ("protected", KeyType::StringList),
+    ("max_remove", KeyType::Integer),
+    ("max_remove_percent", KeyType::Integer),
+    ("replaces", KeyType::StringMap),
+    ("clean_query_output", KeyType::Bool),
+    ("non_utf8_names", KeyType::String),
+    ("allow_exit_codes", KeyType::IntegerList),
+    ("manage_install_reason", KeyType::Bool),
+    ("handle_pacnew", KeyType::Bool),
+    ("reinstall", KeyType::Bool),
+    ("noconfirm", KeyType::Bool),
+    ("check_db_lock", KeyType::Bool),
+    ("db_lock_file", KeyType::String),
+    // Command overrides
+    ("installed_packages_cmd", KeyType::Command),
+    ("dependency_packages_cmd", KeyType::Command),
+    ("explicitly_installed_cmd", KeyType::Command),
+    ("explicitly_unrequired_cmd", KeyType::Command),
+    ("as_explicit_cmd", KeyType::Command),
+    ("install_cmd", KeyType::Command),
+    ("as_dependency_cmd", KeyType::Command),
+    ("remove_cmd", KeyType::Command),
+    ("update_cmd", KeyType::Command),
+    ("get_orphans_cmd", KeyType::Command),
+    ("get_group_packages_cmd", KeyType::Command),
+    ("pacnew_cmd", KeyType::Command),
+    ("dependency_tree_cmd", KeyType::Command),
+    ("local_package_name_cmd", KeyType::Command),
+    ("install_file_cmd", KeyType::Command),
+    ("simulate_install_cmd", KeyType::Command),
+];
+
+/// All keys of an AUR config section: the keys of a pacman section and the AUR helper.
+pub fn aur_keys() -> Vec<(&'static str, KeyType)> {
+    let mut keys = PACMAN_KEYS.to_vec();
+    keys.insert(3, ("helper_cmd", KeyType::String));
+    keys
+}
+
+fn new_system_query_runner(config: &toml::Table, query_env: CommandEnv) -> AResult<SystemQueryRunner> {
+    Ok(SystemQueryRunner {
+        env: query_env,
+        non_utf8: toml_value_to_non_utf8_policy(config.get("non_utf8_names"))?,
+        allow_exit_codes: get_from_table(config, "allow_exit_codes", Vec::new())?,
+    })
+}
+
+pub fn new_pacman(config: &toml::Table, query_env: CommandEnv) -> AResult<PackageSynchronizer> {
+    let runner = new_system_query_runner(config, query_env)?;
+    new_pacman_with_runner(config, Arc::new(runner))
+}
+
+pub fn new_pacman_with_runner(config: &toml::Table, runner: Arc<dyn QueryRunner>) -> AResult<PackageSynchronizer> {
+    new_package_synchronizer(config, runner, &PACMAN_KEYS, None)
+}
+
+/// Synchronizer of the AUR packages. Works like the pacman synchronizer, but installs and updates
+/// with an AUR helper (`helper_cmd`, paru by default) and only manages the foreign packages,
+/// which are not in any repository. Marking and removing is still done with pacman.
+pub fn new_aur(config: &toml::Table, query_env: CommandEnv) -> AResult<PackageSynchronizer> {
+    let runner = new_system_query_runner(config, query_env)?;
+    new_aur_with_runner(config, Arc::new(runner))
+}
+
+pub fn new_aur_with_runner(config: &toml::Table, runner: Arc<dyn QueryRunner>) -> AResult<PackageSynchronizer> {
+    let helper_cmd: String = get_from_table(config, "helper_cmd", "paru".to_string())?;
+    if helper_cmd.is_empty() {
+        return Err("helper_cmd: Command is empty.".into());
+    }
+    if runner.is_root()? {
+        return Err(format!(
+            "{} refuses to build packages as root. Run the AUR synchronizer as a regular user.",
+            helper_cmd
+        )
+        .into());
+    }
+    new_package_synchronizer(config, runner, &aur_keys(), Some(helper_cmd))
+}
+
+/// Name of the binary of a command, which may be given as a path.
+fn binary_name(cmd: &str) -> String {
+    Path::new(cmd)
+        .file_name()
+        .map_or(cmd.to_string(), |n| n.to_string_lossy().to_string())
+}
 
+/// Constructs a pacman synchronizer, or an AUR synchronizer if an AUR helper is given.
+fn new_package_synchronizer(
+    config: &toml::Table,
+    runner: Arc<dyn QueryRunner>,
+    keys: &[(&str, KeyType)],
+    helper_cmd: Option<String>,
+) -> AResult<PackageSynchronizer> {
     // Check for unknown keys
     for k in config.keys() {
-        if !allowed_keys.contains(&k.as_str()) {
+        if !keys.iter().any(|(key, _)| key == k) {
             return Err(format!("Unknown key: {}", k).into());
         }
     }
 
-    let sudo_cmd = get_from_table(config, "sudo_cmd", "sudo".to_string())?;
+    // An empty sudo_cmd runs the modifying commands directly, e.g. as root in a container
+    let sudo_cmd = match config.get("sudo_cmd") {
+        Some(val) => toml_value_to_cmd_array("sudo_cmd", val)?,
+        // Root does not need sudo, which may not even be installed
+        None if runner.is_root()? => Vec::new(),
+        None => vec!["sudo".to_string()],
+    };
+    let pacman_cmd: String = get_from_table(config, "pacman_cmd", "pacman".to_string())?;
+    if pacman_cmd.is_empty() {
+        return Err("pacman_cmd: Command is empty.".into());
+    }
 
-    let pacman_config = PackageSynchronizer {
-        packages: get_from_table(config, "packages", Vec::new())?,
+    let (packages, notes, repos) = toml_value_to_package_list(config.get("packages"))?;
+    // Plain names are compared with a binary search, only the patterns have to be matched one by one
+    let (blacklist_patterns, blacklist): (Vec<String>, Vec<String>) =
+        get_from_table::<Vec<String>>(config, "blacklist", Vec::new())?
+            .into_iter()
+            .partition(|b| is_glob_pattern(b));
+
+    let mut pacman_config = PackageSynchronizer {
+        name: get_from_table(config, "name", String::new())?,
+        packages,
         groups: get_from_table(config, "groups", Vec::new())?,
-        blacklist: get_from_table(config, "blacklist", Vec::new())?,
+        blacklist,
+        blacklist_patterns,
+        local_packages: get_from_table(config, "local_packages", Vec::new())?,
+        hold: get_from_table(config, "hold", Vec::new())?,
+        ignore: get_from_table(config, "ignore", Vec::new())?,
+        protected: get_from_table(
+            config,
+            "protected",
+            DEFAULT_PROTECTED.iter().map(|p| p.to_string()).collect(),
+        )?,
+        max_remove: get_from_table(config, "max_remove", DEFAULT_MAX_REMOVE)?,
+        max_remove_percent: get_from_table(config, "max_remove_percent", None)?,
+        notes,
+        repos,
+        replaces: get_from_table(config, "replaces", BTreeMap::new())?,
+        manage_install_reason: get_from_table(config, "manage_install_reason", true)?,
+        handle_pacnew: get_from_table(config, "handle_pacnew", false)?,
+        reinstall: get_from_table(config, "reinstall", false)?,
+        check_db_lock: get_from_table(config, "check_db_lock", true)?,
+        allow_remove_sudo: false,
+        allow_empty_config: false,
+        allow_empty_groups: false,
+        force: false,
+        no_remove: false,
+        query_jobs: default_query_jobs(),
+        source: String::new(),
         meta: PackageSynchronizerMeta {
-            installed_packages_cmd: vec!["pacman".to_string(), "-Qnq".to_string()],
-            dependency_packages_cmd: vec!["pacman".to_string(), "-Qnqd".to_string()],
-            explicitly_installed_cmd: vec!["pacman".to_string(), "-Qnqe".to_string()],
-            explicitly_unrequired_cmd: vec!["pacman".to_string(), "-Qnqet".to_string()],
-            as_explicit_cmd: vec![
-                sudo_cmd.clone(),
-                "pacman".to_string(),
-                "-D".to_string(),
-                "--asexplicit".to_string(),
-            ],
-            install_cmd: vec![sudo_cmd.clone(), "pacman".to_string(), "-S".to_string()],
-            as_dependency_cmd: vec![
-                sudo_cmd.clone(),
-                "pacman".to_string(),
-                "-D".to_string(),
-                "--asdeps".to_string(),
+            installed_packages_cmd: vec![pacman_cmd.clone(), "-Qnq".to_string()],
+            dependency_packages_cmd: vec![pacman_cmd.clone(), "-Qnqd".to_string()],
+            explicitly_installed_cmd: vec![pacman_cmd.clone(), "-Qnqe".to_string()],
+            explicitly_unrequired_cmd: vec![pacman_cmd.clone(), "-Qnqet".to_string()],
+            as_explicit_cmd: concat(
+                &sudo_cmd,
+                &[pacman_cmd.clone(), "-D".to_string(), "--asexplicit".to_string()],
+            ),
+            install_cmd: concat(&sudo_cmd, &[pacman_cmd.clone(), "-S".to_string()]),
+            as_dependency_cmd: concat(
+                &sudo_cmd,
+                &[pacman_cmd.clone(), "-D".to_string(), "--asdeps".to_string()],
+            ),
+            remove_cmd: concat(&sudo_cmd, &[pacman_cmd.clone(), "-Rs".to_string()]),
+            update_cmd: concat(&sudo_cmd, &[pacman_cmd.clone(), "-Syu".to_string()]),
+            get_orphans_cmd: vec![pacman_cmd.clone(), "-Qnqdt".to_string()],
+            get_group_packages_cmd: vec![pacman_cmd.clone(), "-Sqg".to_string()],
+            kernel_release_cmd: vec!["uname".to_string(), "-r".to_string()],
+            file_owner_cmd: vec![pacman_cmd.clone(), "-Qqo".to_string()],
+            pacnew_cmd: vec!["pacdiff".to_string(), "-o".to_string()],
+            dependency_tree_cmd: vec!["pactree".to_string(), "-lu".to_string()],
+            log_file: "/var/log/pacman.log".to_string(),
+            sudo_cmd: sudo_cmd.clone(),
+            db_lock_file: "/var/lib/pacman/db.lck".to_string(),
+            remove_lock_cmd: concat(&sudo_cmd, &["rm".to_string(), "-f".to_string()]),
+            local_package_name_cmd: vec![pacman_cmd.clone(), "-Qqp".to_string()],
+            install_file_cmd: concat(&sudo_cmd, &[pacman_cmd.clone(), "-U".to_string()]),
+            simulate_install_cmd: vec![
+                pacman_cmd.clone(),
+                "-Sp".to_string(),
+                "--print-format".to_string(),
+                "%n".to_string(),
             ],
-            remove_cmd: vec![sudo_cmd.clone(), "pacman".to_string(), "-Rs".to_string()],
-            update_cmd: vec![sudo_cmd.clone(), "pacman".to_string(), "-Syu".to_string()],
-            get_orphans_cmd: vec!["pacman".to_string(), "-Qnqdt".to_string()],
-            get_group_packages_cmd: vec!["pacman".to_string(), "-Sqg".to_string()],
+            clean_query_output: get_from_table(config, "clean_query_output", false)?,
         },
+        runner,
+        query_cache: QueryCache::default(),
     };
 
+    // Overrides have to invoke the configured binaries, which may be given as a path
+    let manager = binary_name(&pacman_cmd);
+    let mut installer = manager.clone();
+
+    if let Some(helper_cmd) = helper_cmd {
+        // AUR packages are the foreign packages. The helper installs them, since pacman cannot build them.
+        // The helper must run as the user, so it asks for the password itself.
+        let meta = &mut pacman_config.meta;
+        meta.installed_packages_cmd = vec![pacman_cmd.clone(), "-Qmq".to_string()];
+        meta.dependency_packages_cmd = vec![pacman_cmd.clone(), "-Qmqd".to_string()];
+        meta.explicitly_installed_cmd = vec![pacman_cmd.clone(), "-Qmqe".to_string()];
+        meta.explicitly_unrequired_cmd = vec![pacman_cmd.clone(), "-Qmqet".to_string()];
+        meta.get_orphans_cmd = vec![pacman_cmd.clone(), "-Qmqdt".to_string()];
+        meta.install_cmd = vec![helper_cmd.clone(), "-S".to_string()];
+        meta.update_cmd = vec![helper_cmd.clone(), "-Syu".to_string()];
+        // Expands AUR groups as well
+        meta.get_group_packages_cmd = vec![helper_cmd.clone(), "-Sqg".to_string()];
+        installer = binary_name(&helper_cmd);
+    }
+
+    let meta = &mut pacman_config.meta;
+    // Follows the DBPath of pacman.conf, if it was changed there
+    if let Some(db_lock_file) = get_from_table(config, "db_lock_file", None)? {
+        meta.db_lock_file = db_lock_file;
+    }
+    // Skip packages that are already up to date, e.g. if they were installed between the query and the install
+    if !pacman_config.reinstall {
+        meta.install_cmd.push("--needed".to_string());
+    }
+    // Only the modifying commands prompt, the queries never do
+    if get_from_table(config, "noconfirm", false)? {
+        for cmd in [
+            &mut meta.install_cmd,
+            &mut meta.install_file_cmd,
+            &mut meta.remove_cmd,
+            &mut meta.update_cmd,
+        ] {
+            cmd.push("--noconfirm".to_string());
+        }
+    }
+
+    // Overrides of the default commands. Critical commands modify the system and name the binary they have to invoke.
+    let cmd_overrides: [(&str, &mut CommandVector, Option<&str>); 16] = [
+        ("installed_packages_cmd", &mut meta.installed_packages_cmd, None),
+        ("dependency_packages_cmd", &mut meta.dependency_packages_cmd, None),
+        ("explicitly_installed_cmd", &mut meta.explicitly_installed_cmd, None),
+        ("explicitly_unrequired_cmd", &mut meta.explicitly_unrequired_cmd, None),
+        ("as_explicit_cmd", &mut meta.as_explicit_cmd, Some(&manager)),
+        ("install_cmd", &mut meta.install_cmd, Some(&installer)),
+        ("as_dependency_cmd", &mut meta.as_dependency_cmd, Some(&manager)),
+        ("remove_cmd", &mut meta.remove_cmd, Some(&manager)),
+        ("update_cmd", &mut meta.update_cmd, Some(&installer)),
+        ("get_orphans_cmd", &mut meta.get_orphans_cmd, None),
+        ("get_group_packages_cmd", &mut meta.get_group_packages_cmd, None),
+        ("pacnew_cmd", &mut meta.pacnew_cmd, None),
+        ("dependency_tree_cmd", &mut meta.dependency_tree_cmd, None),
+        ("local_package_name_cmd", &mut meta.local_package_name_cmd, None),
+        ("install_file_cmd", &mut meta.install_file_cmd, Some(&manager)),
+        ("simulate_install_cmd", &mut meta.simulate_install_cmd, None),
+    ];
+    for (key, cmd, manager) in cmd_overrides {
+        if let Some(val) = config.get(key) {
+            let new_cmd = toml_value_to_cmd_array(key, val)?;
+            validate_cmd_override(key, &new_cmd, manager)?;
+            *cmd = new_cmd;
+        }
+    }
+
+    // The list comparisons rely on sorted lists
+    cleanup_package_list(&mut pacman_config.packages);
+    cleanup_package_list(&mut pacman_config.groups);
+    cleanup_package_list(&mut pacman_config.blacklist);
+    cleanup_package_list(&mut pacman_config.hold);
+    cleanup_package_list(&mut pacman_config.protected);
+
     Ok(pacman_config)
 }
 
 impl PackageSynchronizer {
+    /// Forgets all query results, so that the next plan runs the query commands again.
+    pub fn clear_query_cache(&self) {
+        self.query_cache.clear();
+    }
+
+    /// Runs a query command and brings the result into the sorted form that the list comparisons need.
+    fn query(&self, cmd: &[String]) -> AResult<Vec<String>> {
+        // The up and down changes both need the installed packages and the config state
+        if let Some(packages) = self.query_cache.get(cmd) {
+            return Ok(packages);
+        }
+        let mut packages = self.runner.get_packages(cmd)?;
+        if self.meta.clean_query_output {
+            packages = clean_query_output(packages);
+        }
+        cleanup_package_list(&mut packages);
+        self.query_cache.insert(cmd, &packages);
+        Ok(packages)
+    }
+
+    /// Runs multiple independent queries in parallel, with at most `query_jobs` commands at the same time.
+    /// Returns the results in the order of the commands.
+    fn query_parallel(&self, cmds: &[CommandVector]) -> AResult<Vec<Vec<String>>> {
+        let limiter = JobLimiter::new(self.query_jobs);
+        let results: Vec<Result<Vec<String>, String>> = thread::scope(|scope| {
+            let handles: Vec<_> = cmds
+                .iter()
+                .map(|cmd| scope.spawn(|| limiter.run(|| self.query(cmd).map_err(|e| e.to_string()))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|_| Err("Query thread panicked".to_string())))
+                .collect()
+        });
+        results.into_iter().map(|r| r.map_err(|e| e.into())).collect()
+    }
+
+    /// Finds packages in the config that are dependencies of other packages in the config,
+    /// and would thus be installed even without being listed.
+    /// Packages that depend on each other are never both reported, so that at least one of them stays.
+    pub fn find_redundant_packages(&self) -> AResult<Vec<String>> {
+        let config_state = self.calculate_config_state()?;
+
+        // All dependencies of each config package, without the package itself
+        let cmds: Vec<CommandVector> = config_state
+            .iter()
+            .map(|p| concat(&self.meta.dependency_tree_cmd, std::slice::from_ref(p)))
+            .collect();
+        let mut dependencies = BTreeMap::new();
+        for (p, mut deps) in config_state.iter().zip(self.query_parallel(&cmds)?) {
+            deps.retain(|d| d != p);
+            dependencies.insert(p.clone(), deps);
+        }
+
+        let mut redundant: Vec<String> = Vec::new();
+        for p in &self.packages {
+            let required_by_kept_package = dependencies
+                .iter()
+                .any(|(q, deps)| q != p && !redundant.contains(q) && deps.binary_search(p).is_ok());
+            if required_by_kept_package {
+                redundant.push(p.clone());
+            }
+        }
+
+        Ok(redundant)
+    }
+
+    /// Returns the entries of `packages` that are also members of a configured group.
+    /// They are deduplicated in the config state anyway, so this only informs about entries that could be removed.
+    pub fn get_packages_also_in_groups(&self) -> AResult<Vec<String>> {
+        if self.groups.is_empty() {
+            return Ok(Vec::new());
+        }
+        let group_packages = self.query(&concat(&self.meta.get_group_packages_cmd, &self.groups))?;
+        Ok(compare_lists_in_both(&self.packages, &group_packages))
+    }
+
+    /// Generates a config section with the explicitly installed packages that no other package requires,
+    /// as a starting point for a new config. Only runs queries.
+    pub fn generate_config(&self) -> AResult<String> {
+        let packages = self.query(&self.meta.explicitly_unrequired_cmd)?;
+        let mut lines = vec![
+            "[pacman]".to_string(),
+            "type = \"pacman\"".to_string(),
+            "packages = [".to_string(),
+        ];
+        let mut line = String::new();
+        for p in &packages {
+            let entry = format!("\"{}\",", p.replace('\\', "\\\\").replace('"', "\\\""));
+            if !line.is_empty() && line.len() + 1 + entry.len() > GENERATED_LINE_WIDTH {
+                lines.push(format!("    {}", line));
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&entry);
+        }
+        if !line.is_empty() {
+            lines.push(format!("    {}", line));
+        }
+        lines.push("]".to_string());
+        lines.push(String::new());
+        Ok(lines.join("\n"))
+    }
+
+    /// Returns the explicitly installed packages that are not in the config.
+    /// If `since_boot` is set, only packages that were installed since the last boot are returned.
+    pub fn get_untracked_explicit(&self, since_boot: bool) -> AResult<Vec<String>> {
+        let config_state = self.calculate_config_state()?;
+        let explicitly_installed_packages = self.query(&self.meta.explicitly_installed_cmd)?;
+        let untracked = compare_lists_only_in_first(&explicitly_installed_packages, &config_state);
+        if !since_boot {
+            return Ok(untracked);
+        }
+
+        let log = fs::read_to_string(&self.meta.log_file)
+            .map_err(|e| format!("Could not read {}: {}", self.meta.log_file, e))?;
+        let installed_since_boot = packages_installed_since(&log, get_boot_time()?);
+        Ok(compare_lists_in_both(&untracked, &installed_since_boot))
+    }
+
+    /// Returns the installed packages whose install reason does not match the config,
+    /// as the packages that should be explicit and the packages that should be dependencies.
+    /// Read only, and independent of `manage_install_reason` and `--no-remove`.
+    pub fn get_install_reason_mismatches(&self) -> AResult<(Vec<String>, Vec<String>)> {
+        let mut synchronizer = self.clone();
+        synchronizer.manage_install_reason = true;
+        synchronizer.no_remove = false;
+        // Only the marks are of interest, the empty config check and the removal limits are about removals
+        synchronizer.allow_empty_config = true;
+        synchronizer.force = true;
+
+        let (should_be_explicit, _, _) = synchronizer.calculate_up_changes()?;
+        let (should_be_dependency, _) = synchronizer.calculate_down_changes()?;
+        Ok((should_be_explicit, should_be_dependency))
+    }
+
+    /// Checks if the pacman database is locked, and if so, whether pacman is actually running.
+    /// Always free if the check is disabled in the config.
+    pub fn check_db_lock(&self) -> AResult<DbLockState> {
+        if !self.check_db_lock || !Path::new(&self.meta.db_lock_file).exists() {
+            return Ok(DbLockState::Free);
+        }
+        if is_process_running("pacman")? {
+            Ok(DbLockState::Held)
+        } else {
+            Ok(DbLockState::Stale)
+        }
+    }
+
+    /// Readable description of the resolved command templates, after sudo prefixing and overrides.
+    pub fn describe_meta(&self) -> Vec<String> {
+        self.meta.describe()
+    }
+
+    pub fn set_allow_remove_sudo(&mut self, allow: bool) {
+        self.allow_remove_sudo = allow;
+    }
+
+    pub fn set_allow_empty_config(&mut self, allow: bool) {
+        self.allow_empty_config = allow;
+    }
+
+    pub fn set_allow_empty_groups(&mut self, allow: bool) {
+        self.allow_empty_groups = allow;
+    }
+
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    pub fn set_query_jobs(&mut self, jobs: usize) {
+        self.query_jobs = jobs;
+    }
+
+    pub fn set_source(&mut self, source: &str) {
+        self.source = source.to_string();
+    }
+
+    pub fn get_db_lock_file(&self) -> &str {
+        &self.meta.db_lock_file
+    }
+
+    /// Command that removes a stale database lock.
+    pub fn get_remove_lock_cmd(&self) -> CommandVector {
+        concat(
+            &self.meta.remove_lock_cmd,
+            std::slice::from_ref(&self.meta.db_lock_file),
+        )
+    }
+
+    /// Returns the note of a package, if the config has one.
+    pub fn get_note(&self, package: &str) -> Option<&str> {
+        self.notes.get(package).map(String::as_str)
+    }
+
+    /// The package name, followed by its note in parentheses if the config has one.
+    pub fn with_note(&self, package: &str) -> String {
+        match self.get_note(package) {
+            Some(note) => format!("{} ({})", package, note),
+            None => package.to_string(),
+        }
+    }
+
+    /// Expands the given groups into their packages and removes all blacklisted packages.
+    pub fn expand_groups(&self, groups: &[String]) -> AResult<Vec<String>> {
+        if groups.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut cmd = self.meta.get_group_packages_cmd.clone();
+        cmd.extend_from_slice(groups);
+        let group_packages = self.query(&cmd)?;
+        let mut expanded = self.remove_blacklisted(&group_packages);
+        cleanup_package_list(&mut expanded);
+        Ok(expanded)
+    }
+
+    fn is_blacklisted(&self, package: &String) -> bool {
+        self.blacklist.binary_search(package).is_ok() || self.blacklist_patterns.iter().any(|b| glob_match(b, package))
+    }
+
+    fn remove_blacklisted(&self, packages: &[String]) -> Vec<String> {
+        packages.iter().filter(|p| !self.is_blacklisted(p)).cloned().collect()
+    }
+
     fn calculate_config_state(&self) -> AResult<Vec<String>> {
+        Ok(self.calculate_config_sources()?.into_keys().collect())
+    }
+
+    /// Packages of the config state, each with the config entries it comes from (like "member of group g").
+    fn calculate_config_sources(&self) -> AResult<BTreeMap<String, Vec<String>>> {
         // Check if packages and blacklist have an overlap. Error if so.
-        let conflicts = compare_lists_in_both(&self.packages, &self.blacklist);
+        let conflicts: Vec<String> = concat(&self.packages, &self.hold)
+            .into_iter()
+            .filter(|p| self.is_blacklisted(p))
+            .collect();
         if !conflicts.is_empty() {
-            return Err(format!("Packages and Blacklist have an overlap: {}", conflicts.join(", ")).into());
+            // Sections are never merged across files, so both sides of the conflict come from the same section
+            let source = if self.source.is_empty() {
+                String::new()
+            } else {
+                format!(" in {}", self.source)
+            };
+            return Err(format!(
+                "Packages and Blacklist{} have an overlap: {}",
+                source,
+                conflicts.join(", ")
+            )
+            .into());
         }
 
-        let mut config_state = self.packages.clone();
+        let mut config_sources: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut add_source = |p: &String, source: String| config_sources.entry(p.clone()).or_default().push(source);
+        for p in &self.packages {
+            add_source(p, "in packages".to_string());
+        }
+        // Held packages stay installed
+        for p in &self.hold {
+            add_source(p, "held".to_string());
+        }
         if !self.groups.is_empty() {
-            // Create cmd array
-            let mut cmd = self.meta.get_group_packages_cmd.clone();
-            cmd.extend(self.groups.clone());
-            // Get all packages in the groups
-            let group_packages = get_packages_from_command(&cmd)?;
-            // Add the group packages to the config state
-            config_state.extend(group_packages);
-            // Remove all blacklisted packages
-            config_state = compare_lists_only_in_first(&config_state, &self.blacklist);
+            // Query each group on its own, to detect groups without packages
+            let cmds: Vec<CommandVector> = self
+                .groups
+                .iter()
+                .map(|g| concat(&self.meta.get_group_packages_cmd, std::slice::from_ref(g)))
+                .collect();
+            let mut empty_groups = Vec::new();
+            for (group, group_packages) in self.groups.iter().zip(self.query_parallel(&cmds)?) {
+                if group_packages.is_empty() {
+                    empty_groups.push(group.clone());
+                }
+                // Add the group packages to the config state
+                for p in &group_packages {
+                    add_source(p, format!("member of group {}", group));
+                }
+            }
+
+            // An empty group is most likely a typo in the group name
+            if !empty_groups.is_empty() {
+                if !self.allow_empty_groups {
+                    return Err(format!(
+                        "Groups without packages: {}. Check the group names or use --allow-empty-groups.",
+                        empty_groups.join(", ")
+                    )
+                    .into());
+                }
+                log_warn!("Groups without packages: {}", empty_groups.join(", "));
+            }
         }
+        // Remove all blacklisted packages
+        config_sources.retain(|p, _| !self.is_blacklisted(p));
 
-        cleanup_package_list(&mut config_state);
-        Ok(config_state)
-    }
-}
+        // Use the new name of renamed packages, so that the installed package satisfies the config
+        for (old_name, new_name) in &self.replaces {
+            if let Some(sources) = config_sources.remove(old_name) {
+                log_warn!(
+                    "Package {} is replaced by {}. Consider updating the config.",
+                    old_name,
+                    new_name
+                );
+                let renamed = sources.into_iter().map(|s| format!("{} as {}", s, old_name));
+                config_sources.entry(new_name.clone()).or_default().extend(renamed);
+            }
+        }
 
-impl SystemConfigSynchronizer for PackageSynchronizer {
-    fn get_pre_cmds(&self) -> AResult<Vec<CommandVector>> {
-        SOk(self.meta.update_cmd.clone())
+        for (path, name) in self.get_local_package_names()? {
+            config_sources
+                .entry(name)
+                .or_default()
+                .push(format!("local file {}", path));
+        }
+
+        Ok(config_sources)
     }
 
-    fn get_post_cmds(&self) -> AResult<Vec<CommandVector>> {
-        let orphans = get_packages_from_command(&self.meta.get_orphans_cmd)?;
-        SOk(concat(&self.meta.remove_cmd, &orphans))
+    /// Reason for each planned change, like "install d: member of group g".
+    pub fn get_change_reasons(&self) -> AResult<Vec<String>> {
+        let config_sources = self.calculate_config_sources()?;
+        let diff = self.get_diff()?;
+        let sources = |p: &String| config_sources.get(p).map(|s| s.join(", ")).unwrap_or_default();
+
+        let mut lines = Vec::new();
+        for p in &diff.to_install {
+            lines.push(format!("install {}: {}", p, sources(p)));
+        }
+        for path in &diff.to_install_files {
+            lines.push(format!("install {}: in local_packages, but not installed", path));
+        }
+        for p in &diff.to_mark_explicit {
+            lines.push(format!(
+                "mark explicit {}: {}, but installed as dependency",
+                p,
+                sources(p)
+            ));
+        }
+        for p in &diff.to_mark_dependency {
+            lines.push(format!(
+                "mark dependency {}: not in the config, explicitly installed and required by other packages",
+                p
+            ));
+        }
+        for p in &diff.to_remove {
+            lines.push(format!(
+                "remove {}: not in the config, explicitly installed and required by nothing",
+                p
+            ));
+        }
+        Ok(lines)
     }
 
-    fn get_up_cmds(&self) -> AResult<Vec<CommandVector>> {
+    /// Tree of where each package of the config state comes from, with the blacklisted and renamed
+    /// packages annotated. Groups are queried one by one, to attribute the packages to their group.
+    pub fn get_provenance_tree(&self) -> AResult<Vec<String>> {
+        let renamed = |p: &String| match self.replaces.get(p) {
+            Some(new_name) => format!("{} (replaced by {})", p, new_name),
+            None => p.clone(),
+        };
+
+        let mut lines = vec![format!(
+            "pacman synchronizer{}",
+            if self.source.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", self.source)
+            }
+        )];
+
+        lines.push("  packages".to_string());
+        for p in &self.packages {
+            lines.push(format!("    {}", renamed(p)));
+        }
+
+        lines.push("  groups".to_string());
+        for g in &self.groups {
+            lines.push(format!("    {}", g));
+            let mut group_packages = self.query(&concat(&self.meta.get_group_packages_cmd, std::slice::from_ref(g)))?;
+            cleanup_package_list(&mut group_packages);
+            for p in &group_packages {
+                if self.is_blacklisted(p) {
+                    lines.push(format!("      {} (blacklisted)", p));
+                } else {
+                    lines.push(format!("      {}", renamed(p)));
+                }
+            }
+        }
+
+        lines.push("  hold".to_string());
+        for p in &self.hold {
+            lines.push(format!("    {}", renamed(p)));
+        }
+
+        lines.push("  local_packages".to_string());
+        for (path, name) in self.get_local_package_names()? {
+            lines.push(format!("    {} -> {}", path, name));
+        }
+
         let config_state = self.calculate_config_state()?;
-        let installed_packages = get_packages_from_command(&self.meta.installed_packages_cmd)?;
-        let dependency_packages = get_packages_from_command(&self.meta.dependency_packages_cmd)?;
+        lines.push(format!("  config state ({} packages)", config_state.len()));
+        for p in config_state {
+            lines.push(format!("    {}", p));
+        }
+        Ok(lines)
+    }
+
+    /// Install arguments for the packages: with the repository prefix, if one is given in the config.
+    fn install_targets(&self, packages: &[String]) -> Vec<String> {
+        packages
+            .iter()
+            .map(|p| match self.repos.get(p) {
+                Some(repo) => format!("{}/{}", repo, p),
+                None => p.clone(),
+            })
+            .collect()
+    }
+
+    /// Simulates the install of the missing config packages and returns the dependencies
+    /// that would be newly installed with them.
+    pub fn get_new_dependencies(&self) -> AResult<Vec<String>> {
+        let (_, to_install, _) = self.calculate_up_changes()?;
+        if to_install.is_empty() {
+            return Ok(Vec::new());
+        }
+        let all_targets = self.query(&concat(
+            &self.meta.simulate_install_cmd,
+            &self.install_targets(&to_install),
+        ))?;
+        Ok(compare_lists_only_in_first(&all_targets, &to_install))
+    }
+
+    /// Reads the package names of the local package files.
+    /// Returns the path and the package name of each file.
+    fn get_local_package_names(&self) -> AResult<Vec<(String, String)>> {
+        let mut names = Vec::new();
+        for path in &self.local_packages {
+            let name = self.query(&concat(&self.meta.local_package_name_cmd, std::slice::from_ref(path)))?;
+            match &name[..] {
+                [name] => names.push((path.clone(), name.clone())),
+                _ => return Err(format!("Could not read the package name of {}", path).into()),
+            }
+        }
+        Ok(names)
+    }
+
+    /// Returns the packages to mark as explicit, the packages to install and the package files to install.
+    fn calculate_up_changes(&self) -> AResult<(Vec<String>, Vec<String>, Vec<String>)> {
+        self.calculate_up_changes_for(&self.calculate_config_state()?)
+    }
+
+    /// Like `calculate_up_changes`, for an already calculated config state.
+    fn calculate_up_changes_for(&self, config_state: &[String]) -> AResult<(Vec<String>, Vec<String>, Vec<String>)> {
+        let installed_packages = self.query(&self.meta.installed_packages_cmd)?;
+        let dependency_packages = self.query(&self.meta.dependency_packages_cmd)?;
+
+        // Local packages are not in any repository and have to be installed from their file
+        let local_packages = self.get_local_package_names()?;
+        let mut local_names: Vec<String> = local_packages.iter().map(|(_, name)| name.clone()).collect();
+        cleanup_package_list(&mut local_names);
+        let mut to_install_files: Vec<String> = local_packages
+            .into_iter()
+            .filter(|(_, name)| installed_packages.binary_search(name).is_err())
+            .map(|(path, _)| path)
+            .collect();
+        // Keep the plan sorted like all other package lists, instead of the config order
+        to_install_files.sort();
+
+        let to_install = compare_lists_only_in_first(config_state, &installed_packages);
+        let to_install = compare_lists_only_in_first(&to_install, &local_names);
+        let to_mark_explicit = if self.manage_install_reason {
+            compare_lists_in_both(config_state, &dependency_packages)
+        } else {
+            Vec::new()
+        };
+
+        Ok((to_mark_explicit, to_install, to_install_files))
+    }
+
+    /// Returns the packages to mark as dependency and the packages to remove.
+    fn calculate_down_changes(&self) -> AResult<(Vec<String>, Vec<String>)> {
+        if self.no_remove {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        self.calculate_down_changes_for(
+            &self.calculate_config_state()?,
+            self.get_running_kernel_package().as_deref(),
+        )
+    }
+
+    /// Like `calculate_down_changes`, for an already calculated config state and running kernel package.
+    fn calculate_down_changes_for(
+        &self,
+        config_state: &[String],
+        kernel: Option<&str>,
+    ) -> AResult<(Vec<String>, Vec<String>)> {
+        if self.no_remove {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        let explicitly_installed_packages = self.query(&self.meta.explicitly_installed_cmd)?;
+        let explicitly_unrequired_packages = self.query(&self.meta.explicitly_unrequired_cmd)?;
+        let explicitly_required_packages =
+            compare_lists_only_in_first(&explicitly_installed_packages, &explicitly_unrequired_packages);
+
+        let mut to_remove = compare_lists_only_in_first(&explicitly_unrequired_packages, config_state);
+        to_remove = compare_lists_only_in_first(&to_remove, &self.protected);
+        let mut to_mark_dependency = compare_lists_only_in_first(&explicitly_required_packages, config_state);
+
+        // Never touch the running kernel. Marking it as dependency would remove it as an orphan later.
+        if let Some(kernel) = kernel {
+            if to_remove.iter().any(|p| p == kernel) || to_mark_dependency.iter().any(|p| p == kernel) {
+                log_warn!(
+                    "The running kernel package {} is not in the config and would have been removed. Keeping it.",
+                    kernel
+                );
+                to_remove.retain(|p| p != kernel);
+                to_mark_dependency.retain(|p| p != kernel);
+            }
+        }
 
-        let to_install = compare_lists_only_in_first(&config_state, &installed_packages);
-        let to_mark_explicit = compare_lists_in_both(&config_state, &dependency_packages);
+        if !self.manage_install_reason {
+            to_mark_dependency.clear();
+        }
+
+        // An empty config is most likely a mistake and would remove every explicitly installed package
+        if config_state.is_empty()
+            && !self.allow_empty_config
+            && !(to_remove.is_empty() && to_mark_dependency.is_empty())
+        {
+            return Err(format!(
+                "The config is empty (no packages, no groups or everything blacklisted). \
+                 This would remove all {} explicitly installed packages. Use --allow-empty-config if this is intended.",
+                to_remove.len() + to_mark_dependency.len()
+            )
+            .into());
+        }
+
+        // A group that failed to expand or a missing config file can easily remove most of the system
+        if !self.force {
+            self.check_remove_limits(to_remove.len())?;
+        }
+
+        // Removing sudo would break all following commands of the run
+        if !self.allow_remove_sudo && !to_remove.is_empty() {
+            if let Some(sudo_package) = self.get_sudo_package() {
+                if to_remove.contains(&sudo_package) {
+                    return Err(format!(
+                        "Refusing to remove {}, which provides {}. Add it to the config or use --allow-remove-sudo.",
+                        sudo_package,
+                        self.meta.sudo_cmd.join(" ")
+                    )
+                    .into());
+                }
+            }
+        }
+
+        Ok((to_mark_dependency, to_remove))
+    }
+
+    /// Errors if the number of packages to remove exceeds `max_remove` or `max_remove_percent`.
+    fn check_remove_limits(&self, remove_count: usize) -> AResult<()> {
+        if remove_count > self.max_remove {
+            return Err(format!(
+                "Refusing to remove {} packages, which is more than max_remove ({}). \
+                 Check the config, or use --force if this is intended.",
+                remove_count, self.max_remove
+            )
+            .into());
+        }
+        if let Some(percent) = self.max_remove_percent {
+            let installed_count = self.query(&self.meta.installed_packages_cmd)?.len();
+            if remove_count * 100 > installed_count * percent {
+                return Err(format!(
+                    "Refusing to remove {} of {} installed packages, which is more than max_remove_percent ({}%). \
+                     Check the config, or use --force if this is intended.",
+                    remove_count, installed_count, percent
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the package that provides `sudo_cmd`.
+    /// Prints a warning and returns `None` if it cannot be detected, and `None` if no sudo_cmd is used.
+    fn get_sudo_package(&self) -> Option<String> {
+        let sudo_binary = self.meta.sudo_cmd.first()?;
+        // pacman -Qo searches the PATH for names without a slash
+        match self.query(&concat(&self.meta.file_owner_cmd, std::slice::from_ref(sudo_binary))) {
+            Ok(p) if p.len() == 1 => Some(p[0].clone()),
+            Ok(_) => {
+                log_warn!("Could not detect the package of {}: no unique owner", sudo_binary);
+                None
+            }
+            Err(e) => {
+                log_warn!("Could not detect the package of {}: {}", sudo_binary, e);
+                None
+            }
+        }
+    }
+
+    /// Returns the package that owns the currently running kernel.
+    /// Prints a warning and returns `None` if it cannot be detected.
+    fn get_running_kernel_package(&self) -> Option<String> {
+        let release = match self.query(&self.meta.kernel_release_cmd) {
+            Ok(r) if r.len() == 1 => r[0].clone(),
+            Ok(_) => {
+                log_warn!("Could not detect the running kernel: unexpected output of kernel release command");
+                return None;
+            }
+            Err(e) => {
+                log_warn!("Could not detect the running kernel: {}", e);
+                return None;
+            }
+        };
+
+        let kernel_image = format!("/usr/lib/modules/{}/vmlinuz", release);
+        match self
+            .runner
+            .get_packages(&concat(&self.meta.file_owner_cmd, &[kernel_image]))
+        {
+            Ok(p) if p.len() == 1 => Some(p[0].clone()),
+            Ok(_) => {
+                log_warn!("Could not detect the running kernel: no unique owner of kernel image");
+                None
+            }
+            Err(e) => {
+                log_warn!("Could not detect the running kernel: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Up commands for the given changes of `calculate_up_changes`.
+    fn up_cmds_for(&self, changes: (Vec<String>, Vec<String>, Vec<String>)) -> Vec<CommandVector> {
+        let (mut to_mark_explicit, mut to_install, to_install_files) = changes;
+
+        // Without --needed, pacman -S reinstalls dependencies and marks them as explicit.
+        // Fold them into the install, so that both happen in a single transaction.
+        // With --needed they would be skipped, so they still need the separate -D.
+        if self.reinstall && !to_install.is_empty() {
+            to_install.append(&mut to_mark_explicit);
+            cleanup_package_list(&mut to_install);
+        }
 
         let mut cmd_list = Vec::new();
 
@@ -213,22 +1703,20 @@ impl SystemConfigSynchronizer for PackageSynchronizer {
             cmd_list.push(as_explicit_cmd);
         }
         if !to_install.is_empty() {
-            let to_install_cmd = concat(&self.meta.install_cmd, &to_install);
+            let to_install_cmd = concat(&self.meta.install_cmd, &self.install_targets(&to_install));
             cmd_list.push(to_install_cmd);
         }
+        if !to_install_files.is_empty() {
+            let install_file_cmd = concat(&self.meta.install_file_cmd, &to_install_files);
+            cmd_list.push(install_file_cmd);
+        }
 
-        Ok(cmd_list)
+        cmd_list
     }
 
-    fn get_down_cmds(&self) -> AResult<Vec<CommandVector>> {
-        let config_state = self.calculate_config_state()?;
-        let explicitly_installed_packages = get_packages_from_command(&self.meta.explicitly_installed_cmd)?;
-        let explicitly_unrequired_packages = get_packages_from_command(&self.meta.explicitly_unrequired_cmd)?;
-        let explicitly_required_packages =
-            compare_lists_only_in_first(&explicitly_installed_packages, &explicitly_unrequired_packages);
-
-        let to_remove = compare_lists_only_in_first(&explicitly_unrequired_packages, &config_state);
-        let to_mark_dependency = compare_lists_only_in_first(&explicitly_required_packages, &config_state);
+    /// Down commands for the given changes of `calculate_down_changes`.
+    fn down_cmds_for(&self, changes: (Vec<String>, Vec<String>)) -> Vec<CommandVector> {
+        let (to_mark_dependency, to_remove) = changes;
 
         let mut cmd_list = Vec::new();
 
@@ -241,6 +1729,615 @@ impl SystemConfigSynchronizer for PackageSynchronizer {
             cmd_list.push(remove_cmd);
         }
 
+        cmd_list
+    }
+
+    /// Orphan cleanup for the given config state and running kernel package.
+    /// The config state is only used without `manage_install_reason`.
+    fn post_cmds_for(&self, config_state: &[String], kernel: Option<&str>) -> AResult<Vec<CommandVector>> {
+        let mut orphans = compare_lists_only_in_first(&self.query(&self.meta.get_orphans_cmd)?, &self.protected);
+        if let Some(kernel) = kernel {
+            orphans.retain(|p| p != kernel);
+        }
+        if !self.manage_install_reason {
+            // Config packages may still be installed as dependency
+            orphans = compare_lists_only_in_first(&orphans, config_state);
+        }
+        if orphans.is_empty() {
+            // pacman fails without targets, which would abort the run
+            return Ok(Vec::new());
+        }
+        SOk(concat(&self.meta.remove_cmd, &orphans))
+    }
+}
+
+impl SystemConfigSynchronizer for PackageSynchronizer {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_no_remove(&mut self, no_remove: bool) {
+        self.no_remove = no_remove;
+    }
+
+    fn get_pre_cmds(&self) -> AResult<Vec<CommandVector>> {
+        let mut update_cmd = self.meta.update_cmd.clone();
+        for p in self.hold.iter().chain(&self.ignore) {
+            update_cmd.push("--ignore".to_string());
+            update_cmd.push(p.clone());
+        }
+        let mut cmd_list = vec![update_cmd];
+        if self.handle_pacnew {
+            // Lists the .pacnew and .pacsave files of the upgrade, so they are not forgotten
+            cmd_list.push(self.meta.pacnew_cmd.clone());
+        }
         Ok(cmd_list)
     }
+
+    fn get_post_cmds(&self) -> AResult<Vec<CommandVector>> {
+        if self.no_remove {
+            // The orphan cleanup removes packages as well
+            return Ok(Vec::new());
+        }
+
+        let kernel = self.get_running_kernel_package();
+        if self.manage_install_reason {
+            self.post_cmds_for(&[], kernel.as_deref())
+        } else {
+            self.post_cmds_for(&self.calculate_config_state()?, kernel.as_deref())
+        }
+    }
+
+    fn get_up_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(self.up_cmds_for(self.calculate_up_changes()?))
+    }
+
+    fn get_down_cmds(&self) -> AResult<Vec<CommandVector>> {
+        Ok(self.down_cmds_for(self.calculate_down_changes()?))
+    }
+
+    /// Calculates the config state and the running kernel package only once for all phases,
+    /// so that the warnings about them are not repeated.
+    fn plan(&self, phases: &[&str]) -> AResult<Plan> {
+        let runs = |phase: &str| phases.contains(&phase);
+        let config_state = if runs("Up") || runs("Down") || runs("Post") {
+            self.calculate_config_state()?
+        } else {
+            Vec::new()
+        };
+        let kernel = if !self.no_remove && (runs("Down") || runs("Post")) {
+            self.get_running_kernel_package()
+        } else {
+            None
+        };
+
+        let mut plan = Plan::default();
+        if runs("Pre") {
+            plan.pre = self.get_pre_cmds()?;
+        }
+        if runs("Up") {
+            let changes = self.calculate_up_changes_for(&config_state)?;
+            plan.up_changes = changes.0.len() + changes.1.len() + changes.2.len();
+            plan.up = self.up_cmds_for(changes);
+        }
+        if runs("Down") {
+            let changes = self.calculate_down_changes_for(&config_state, kernel.as_deref())?;
+            plan.down_changes = changes.0.len() + changes.1.len();
+            plan.down = self.down_cmds_for(changes);
+        }
+        if runs("Post") && !self.no_remove {
+            plan.post = self.post_cmds_for(&config_state, kernel.as_deref())?;
+        }
+        Ok(plan)
+    }
+
+    fn count_up_changes(&self) -> AResult<usize> {
+        let (to_mark_explicit, to_install, to_install_files) = self.calculate_up_changes()?;
+        Ok(to_mark_explicit.len() + to_install.len() + to_install_files.len())
+    }
+
+    fn count_down_changes(&self) -> AResult<usize> {
+        let (to_mark_dependency, to_remove) = self.calculate_down_changes()?;
+        Ok(to_mark_dependency.len() + to_remove.len())
+    }
+
+    fn get_explanation(&self) -> AResult<Vec<(String, Vec<String>)>> {
+        let config_state = self.calculate_config_state()?;
+        let mut sections = vec![(
+            "Config state".to_string(),
+            config_state.iter().map(|p| self.with_note(p)).collect(),
+        )];
+
+        for cmd in [
+            &self.meta.installed_packages_cmd,
+            &self.meta.dependency_packages_cmd,
+            &self.meta.explicitly_installed_cmd,
+            &self.meta.explicitly_unrequired_cmd,
+            &self.meta.get_orphans_cmd,
+        ] {
+            sections.push((format!("Query: {}", cmd.join(" ")), self.query(cmd)?));
+        }
+
+        sections.extend(self.get_planned_changes()?);
+
+        Ok(sections)
+    }
+
+    fn get_diff(&self) -> AResult<SyncDiff> {
+        let (to_mark_explicit, to_install, to_install_files) = self.calculate_up_changes()?;
+        let (to_mark_dependency, to_remove) = self.calculate_down_changes()?;
+        Ok(SyncDiff {
+            to_install,
+            to_install_files,
+            to_remove,
+            to_mark_explicit,
+            to_mark_dependency,
+        })
+    }
+
+    fn get_planned_changes(&self) -> AResult<Vec<(String, Vec<String>)>> {
+        let diff = self.get_diff()?;
+        Ok(vec![
+            ("Mark as explicit".to_string(), diff.to_mark_explicit),
+            ("Install".to_string(), diff.to_install),
+            ("Install from file".to_string(), diff.to_install_files),
+            ("Mark as dependency".to_string(), diff.to_mark_dependency),
+            ("Remove".to_string(), diff.to_remove),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::self_test::{mock_runner, mock_synchronizer};
+    use crate::test_util::{cmd, section, str_array, CountingQueryRunner};
+
+    use std::sync::atomic::Ordering;
+    use toml::Value;
+
+    /// Pacman synchronizer with the given config keys, on the system of `mock_runner`.
+    fn pacman(entries: &[(&str, Value)]) -> PackageSynchronizer {
+        new_pacman_with_runner(&section("pacman", entries), Arc::new(mock_runner())).unwrap()
+    }
+
+    #[test]
+    fn phases() {
+        let s = mock_synchronizer().unwrap();
+        assert_eq!(s.get_pre_cmds().unwrap(), vec![cmd(&["sudo", "pacman", "-Syu"])]);
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![
+                cmd(&["sudo", "pacman", "-D", "--asexplicit", "b"]),
+                cmd(&["sudo", "pacman", "-S", "--needed", "c", "d"]),
+            ]
+        );
+        assert_eq!(
+            s.get_down_cmds().unwrap(),
+            vec![
+                cmd(&["sudo", "pacman", "-D", "--asdeps", "y"]),
+                cmd(&["sudo", "pacman", "-Rs", "x"]),
+            ]
+        );
+        assert_eq!(s.get_post_cmds().unwrap(), vec![cmd(&["sudo", "pacman", "-Rs", "z"])]);
+    }
+
+    #[test]
+    fn diff() {
+        let diff = mock_synchronizer().unwrap().get_diff().unwrap();
+        assert_eq!(diff.to_install, cmd(&["c", "d"]));
+        assert_eq!(diff.to_remove, cmd(&["x"]));
+    }
+
+    #[test]
+    fn blacklist_without_groups() {
+        let mut replaces = toml::Table::new();
+        replaces.insert("c-old".to_string(), Value::String("c".to_string()));
+        let s = pacman(&[
+            ("packages", str_array(&["a", "c-old"])),
+            ("blacklist", str_array(&["b", "e"])),
+            ("replaces", Value::Table(replaces)),
+        ]);
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-S", "--needed", "c"])]
+        );
+    }
+
+    #[test]
+    fn group_overlap_and_empty_groups() {
+        let mut s = pacman(&[
+            ("packages", str_array(&["a", "d"])),
+            ("groups", str_array(&["g", "typo"])),
+        ]);
+        assert_eq!(s.get_packages_also_in_groups().unwrap(), cmd(&["d"]));
+        assert!(s.get_up_cmds().is_err());
+
+        s.set_allow_empty_groups(true);
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-S", "--needed", "d", "e"])]
+        );
+    }
+
+    #[test]
+    fn blacklist_with_patterns() {
+        let s = pacman(&[
+            ("groups", str_array(&["tex"])),
+            ("blacklist", str_array(&["texlive-*", "tex?", "other"])),
+        ]);
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-S", "--needed", "keep", "texinfo"])]
+        );
+    }
+
+    #[test]
+    fn no_remove() {
+        let mut s = mock_synchronizer().unwrap();
+        s.set_no_remove(true);
+        assert!(s.get_down_cmds().unwrap().is_empty());
+        assert!(s.get_post_cmds().unwrap().is_empty());
+        assert_eq!(s.get_install_reason_mismatches().unwrap(), (cmd(&["b"]), cmd(&["y"])));
+    }
+
+    #[test]
+    fn protected_packages() {
+        let s = pacman(&[
+            ("packages", str_array(&["a", "b"])),
+            ("protected", str_array(&["x", "z"])),
+        ]);
+        assert_eq!(
+            s.get_down_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-D", "--asdeps", "y"])]
+        );
+        assert!(s.get_post_cmds().unwrap().is_empty());
+    }
+
+    #[test]
+    fn max_remove() {
+        let mut s = pacman(&[("packages", str_array(&["a", "b"])), ("max_remove", Value::Integer(0))]);
+        assert!(s.get_down_cmds().is_err());
+
+        s.set_force(true);
+        assert_eq!(
+            s.get_down_cmds().unwrap(),
+            vec![
+                cmd(&["sudo", "pacman", "-D", "--asdeps", "y"]),
+                cmd(&["sudo", "pacman", "-Rs", "x"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_removals_with_empty_config() {
+        assert!(pacman(&[]).get_down_cmds().is_err());
+    }
+
+    #[test]
+    fn aur() {
+        let config = section("aur", &[("packages", str_array(&["aur-a", "aur-new"]))]);
+        let s = new_aur_with_runner(&config, Arc::new(mock_runner())).unwrap();
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["paru", "-S", "--needed", "aur-new"])]
+        );
+        assert_eq!(
+            s.get_down_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-Rs", "aur-old"])]
+        );
+    }
+
+    #[test]
+    fn aur_groups() {
+        let runner = mock_runner().respond("paru -Sqg aur-group", &["aur-b", "aur-a"]);
+        let config = section(
+            "aur",
+            &[
+                ("packages", str_array(&["aur-new"])),
+                ("groups", str_array(&["aur-group"])),
+            ],
+        );
+        let s = new_aur_with_runner(&config, Arc::new(runner)).unwrap();
+        // aur-a is installed already, so only the other group member is installed
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["paru", "-S", "--needed", "aur-b", "aur-new"])]
+        );
+        assert_eq!(
+            s.get_down_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-Rs", "aur-old"])]
+        );
+    }
+
+    #[test]
+    fn aur_rejected_as_root() {
+        let config = section("aur", &[("packages", str_array(&["aur-a"]))]);
+        let e = new_aur_with_runner(&config, Arc::new(mock_runner().running_as_root())).unwrap_err();
+        assert!(e.to_string().contains("as root"));
+    }
+
+    #[test]
+    fn reinstall_and_noconfirm() {
+        let reinstall = [
+            ("packages", str_array(&["b", "n"])),
+            ("reinstall", Value::Boolean(true)),
+        ];
+        assert_eq!(
+            pacman(&reinstall).get_up_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-S", "b", "n"])]
+        );
+
+        let mut noconfirm = reinstall.to_vec();
+        noconfirm.push(("noconfirm", Value::Boolean(true)));
+        let s = pacman(&noconfirm);
+        assert_eq!(
+            s.get_pre_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-Syu", "--noconfirm"])]
+        );
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-S", "--noconfirm", "b", "n"])]
+        );
+    }
+
+    #[test]
+    fn ignored_and_held_packages() {
+        let s = pacman(&[("ignore", str_array(&["nvidia", "nvidia-utils"]))]);
+        assert_eq!(
+            s.get_pre_cmds().unwrap(),
+            vec![cmd(&[
+                "sudo",
+                "pacman",
+                "-Syu",
+                "--ignore",
+                "nvidia",
+                "--ignore",
+                "nvidia-utils"
+            ])]
+        );
+
+        let s = pacman(&[
+            ("packages", str_array(&["core/a", "extra/n"])),
+            ("hold", str_array(&["y"])),
+        ]);
+        assert_eq!(
+            s.get_pre_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-Syu", "--ignore", "y"])]
+        );
+        assert_eq!(
+            s.get_up_cmds().unwrap(),
+            vec![cmd(&["sudo", "pacman", "-S", "--needed", "extra/n"])]
+        );
+    }
+
+    #[test]
+    fn sudo_cmd() {
+        assert_eq!(
+            pacman(&[("sudo_cmd", str_array(&["sudo", "-A"]))])
+                .get_pre_cmds()
+                .unwrap(),
+            vec![cmd(&["sudo", "-A", "pacman", "-Syu"])]
+        );
+        assert_eq!(
+            pacman(&[("sudo_cmd", Value::String(String::new()))])
+                .get_pre_cmds()
+                .unwrap(),
+            vec![cmd(&["pacman", "-Syu"])]
+        );
+        assert_eq!(
+            pacman(&[("pacman_cmd", Value::String("/opt/bin/pacman-wrapper".to_string()))])
+                .get_pre_cmds()
+                .unwrap(),
+            vec![cmd(&["sudo", "/opt/bin/pacman-wrapper", "-Syu"])]
+        );
+    }
+
+    #[test]
+    fn sudo_cmd_as_root() {
+        let root = Arc::new(mock_runner().running_as_root());
+        let s = new_pacman_with_runner(&section("pacman", &[]), root.clone()).unwrap();
+        assert_eq!(s.get_pre_cmds().unwrap(), vec![cmd(&["pacman", "-Syu"])]);
+
+        let doas = section("pacman", &[("sudo_cmd", Value::String("doas".to_string()))]);
+        let s = new_pacman_with_runner(&doas, root).unwrap();
+        assert_eq!(s.get_pre_cmds().unwrap(), vec![cmd(&["doas", "pacman", "-Syu"])]);
+    }
+
+    #[test]
+    fn new_dependencies() {
+        let s = mock_synchronizer().unwrap();
+        assert_eq!(s.get_new_dependencies().unwrap(), cmd(&["libd"]));
+    }
+
+    #[test]
+    fn generated_config() {
+        let config = mock_synchronizer().unwrap().generate_config().unwrap();
+        assert_eq!(
+            config.lines().collect::<Vec<_>>(),
+            [
+                "[pacman]",
+                "type = \"pacman\"",
+                "packages = [",
+                "    \"a\", \"k\", \"x\",",
+                "]"
+            ]
+        );
+    }
+
+    #[test]
+    fn notes() {
+        let mut noted = toml::Table::new();
+        noted.insert("name".to_string(), Value::String("extra/a".to_string()));
+        noted.insert("note".to_string(), Value::String("needed for work".to_string()));
+        let s = pacman(&[(
+            "packages",
+            Value::Array(vec![Value::Table(noted), Value::String("b".to_string())]),
+        )]);
+        assert_eq!(s.with_note("a"), "a (needed for work)");
+        assert_eq!(s.with_note("b"), "b");
+    }
+
+    #[test]
+    fn name_from_config() {
+        let s = pacman(&[("name", Value::String("workstation".to_string()))]);
+        assert_eq!(s.name(), "workstation");
+    }
+
+    #[test]
+    fn plan_selects_phases() {
+        let s = mock_synchronizer().unwrap();
+        assert_eq!(s.plan(&["Up", "Down"]).unwrap().up, s.get_up_cmds().unwrap());
+        assert!(s.plan(&["Up"]).unwrap().down.is_empty());
+    }
+
+    #[test]
+    fn plan_matches_phases() {
+        for no_remove in [false, true] {
+            let mut s = mock_synchronizer().unwrap();
+            s.set_no_remove(no_remove);
+            let plan = s.plan(&["Pre", "Up", "Down", "Post"]).unwrap();
+            assert_eq!(plan.pre, s.get_pre_cmds().unwrap());
+            assert_eq!(plan.up, s.get_up_cmds().unwrap());
+            assert_eq!(plan.down, s.get_down_cmds().unwrap());
+            assert_eq!(plan.post, s.get_post_cmds().unwrap());
+        }
+    }
+
+    #[test]
+    fn change_reasons() {
+        assert_eq!(
+            mock_synchronizer().unwrap().get_change_reasons().unwrap(),
+            [
+                "install c: in packages as c-old",
+                "install d: member of group g",
+                "mark explicit b: in packages, but installed as dependency",
+                "mark dependency y: not in the config, explicitly installed and required by other packages",
+                "remove x: not in the config, explicitly installed and required by nothing",
+            ]
+        );
+    }
+
+    #[test]
+    fn db_lock() {
+        // Any file that always exists stands in for a held lock
+        let mut entries = vec![("db_lock_file", Value::String("/proc/version".to_string()))];
+        assert_ne!(pacman(&entries).check_db_lock().unwrap(), DbLockState::Free);
+
+        entries.push(("check_db_lock", Value::Boolean(false)));
+        assert_eq!(pacman(&entries).check_db_lock().unwrap(), DbLockState::Free);
+    }
+
+    #[test]
+    fn query_job_limit() {
+        let config = section(
+            "pacman",
+            &[("packages", str_array(&["p1", "p2", "p3", "p4", "p5", "p6", "p7", "p8"]))],
+        );
+        let runner = Arc::new(CountingQueryRunner::default());
+        let mut s = new_pacman_with_runner(&config, runner.clone()).unwrap();
+        s.set_query_jobs(2);
+        s.find_redundant_packages().unwrap();
+        assert!(runner.max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn queries_run_once() {
+        let config = section("pacman", &[("packages", str_array(&["p1", "p2"]))]);
+        let runner = Arc::new(CountingQueryRunner::default());
+        let s = new_pacman_with_runner(&config, runner.clone()).unwrap();
+        s.find_redundant_packages().unwrap();
+        let first_calls = runner.calls.load(Ordering::SeqCst);
+        assert!(first_calls > 0);
+        s.find_redundant_packages().unwrap();
+        assert_eq!(runner.calls.load(Ordering::SeqCst), first_calls);
+
+        s.clear_query_cache();
+        s.find_redundant_packages().unwrap();
+        assert_eq!(runner.calls.load(Ordering::SeqCst), 2 * first_calls);
+    }
+
+    #[test]
+    fn cleanup_of_query_output() {
+        assert_eq!(
+            clean_query_output(cmd(&["\x1b[1;32mfoo\x1b[0m", "\x1b[33mbar", "baz\x1b(B"])),
+            ["foo", "bar", "baz"]
+        );
+        assert_eq!(
+            clean_query_output(cmd(&["", "  foo  ", "   ", "\tbar", "Proceed? [Y/n] "])),
+            ["foo", "bar", "Proceed? [Y/n]"]
+        );
+    }
+
+    #[test]
+    fn non_utf8_query_output() {
+        assert_eq!(
+            split_query_output(b"a\n\xffb\nc\n", NonUtf8Policy::Lossy),
+            ["a", "\u{fffd}b", "c"]
+        );
+        assert_eq!(split_query_output(b"a\n\xffb\nc\n", NonUtf8Policy::Skip), ["a", "c"]);
+    }
+
+    #[test]
+    fn query_exit_codes() {
+        let allowing = SystemQueryRunner {
+            allow_exit_codes: vec![1],
+            ..Default::default()
+        };
+        assert!(allowing.get_packages(&cmd(&["false"])).unwrap().is_empty());
+        assert!(SystemQueryRunner::default().get_packages(&cmd(&["false"])).is_err());
+    }
+
+    #[test]
+    fn query_timeout() {
+        let runner = SystemQueryRunner {
+            env: CommandEnv {
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(runner.get_packages(&cmd(&["sleep", "5"])).is_err());
+    }
+
+    #[test]
+    fn missing_query_command() {
+        let e = SystemQueryRunner::default()
+            .get_packages(&cmd(&["system-config-synchronizer-missing-command"]))
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Command not found: system-config-synchronizer-missing-command"
+        );
+    }
+
+    #[test]
+    fn pacman_log_filter_since_boot() {
+        let log = "[2023-08-01T10:00:00+0200] [ALPM] installed before (1.0-1)\n\
+                   [2023-08-01T12:00:00+0200] [ALPM] upgraded upgraded (1.0-1 -> 1.1-1)\n\
+                   [2023-08-01T12:00:00+0200] [ALPM] installed after (1.0-1)\n\
+                   [2023-08-01 12:00] [ALPM] installed oldformat (1.0-1)\n";
+        // 2023-08-01T11:00:00+0200
+        assert_eq!(packages_installed_since(log, 1690880400), ["after"]);
+    }
+
+    #[test]
+    fn effective_uid() {
+        assert_eq!(
+            parse_effective_uid("Name:\tscs\nUid:\t1000\t0\t0\t0\nGid:\t1000\t1000\t1000\t1000\n"),
+            Some(0)
+        );
+        assert_eq!(
+            parse_effective_uid("Name:\tscs\nUid:\t1000\t1000\t1000\t1000\n"),
+            Some(1000)
+        );
+    }
 }
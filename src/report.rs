@@ -0,0 +1,457 @@
+use crate::package_synchronizer::SyncDiff;
+use crate::{AResult, CommandVector};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Summary of one synchronizer for the run report.
+#[derive(Debug, Clone, Default)]
+pub struct SynchronizerReport {
+    pub name: String,
+    /// `type` of the config section.
+    pub sync_type: String,
+    /// Number of packages touched by the planned up commands. None if the phase was not planned.
+    pub up_changes: Option<usize>,
+    /// Number of packages touched by the planned down commands. None if the phase was not planned.
+    pub down_changes: Option<usize>,
+    /// Planned commands of each phase, in order.
+    pub phases: Vec<(String, Vec<CommandVector>)>,
+    /// Planned changes as package lists. Only computed for the JSON output.
+    pub diff: Option<SyncDiff>,
+}
+
+impl SynchronizerReport {
+    /// Planned commands of all phases, in the same format as the preview.
+    pub fn plan_text(&self) -> String {
+        let mut lines = Vec::new();
+        for (phase, cmds) in &self.phases {
+            lines.push(format!("{} Commands:", phase));
+            for c in cmds {
+                lines.push(format!("> {}", c.join(" ")));
+            }
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+/// Replaces all characters that are not safe in a file name.
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "unnamed".to_string()
+    } else {
+        name
+    }
+}
+
+/// Quotes an argument for a POSIX shell, if necessary.
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Version of the JSON output. Increased on every change that can break consumers.
+pub const JSON_VERSION: u32 = 1;
+
+// The JSON is written by hand instead of with serde_json. The output only consists of strings,
+// arrays of them and a few numbers, which does not justify a dependency.
+
+/// Quotes and escapes a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_string_array(l: &[String]) -> String {
+    format!("[{}]", l.iter().map(|s| json_string(s)).collect::<Vec<_>>().join(", "))
+}
+
+/// Error as JSON object: `{"version": 1, "error": "..."}`.
+pub fn json_error(msg: &str) -> String {
+    format!("{{\"version\": {}, \"error\": {}}}", JSON_VERSION, json_string(msg))
+}
+
+/// 64 bit FNV-1a hash. Unlike the std hashers, its output is guaranteed to be the same across builds.
+fn fnv1a_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// What happened to a command that was up for execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandOutcome {
+    Succeeded,
+    /// Not run, because it was declined at the confirmation prompt.
+    Skipped,
+    /// Failed with this error.
+    Failed(String),
+}
+
+/// A planned command that was up for execution, with its outcome.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutedCommand {
+    pub phase: String,
+    pub cmd: CommandVector,
+    pub outcome: CommandOutcome,
+}
+
+/// Plain text summary of a run, meant to be kept as a CI artifact.
+#[derive(Debug, Clone, Default)]
+pub struct RunReport {
+    pub run_id: String,
+    pub synchronizers: Vec<SynchronizerReport>,
+    /// Config sections that were not processed, with the reason.
+    pub skipped: Vec<String>,
+    pub errors: Vec<String>,
+    /// Commands that were up for execution, in the order they were handled. Empty for dry runs.
+    pub executed: Vec<ExecutedCommand>,
+}
+
+impl RunReport {
+    /// Whether the run had no errors and no command failed.
+    pub fn succeeded(&self) -> bool {
+        self.errors.is_empty()
+            && !self
+                .executed
+                .iter()
+                .any(|e| matches!(e.outcome, CommandOutcome::Failed(_)))
+    }
+
+    /// All planned commands, each with the name of its phase, in the order they run:
+    /// each phase for all synchronizers, before the next phase.
+    pub fn cmds_in_run_order(&self) -> Vec<(&str, &CommandVector)> {
+        let phase_count = self.synchronizers.iter().map(|s| s.phases.len()).max().unwrap_or(0);
+        let mut cmds = Vec::new();
+        for i in 0..phase_count {
+            for sync in &self.synchronizers {
+                if let Some((phase, phase_cmds)) = sync.phases.get(i) {
+                    cmds.extend(phase_cmds.iter().map(|c| (phase.as_str(), c)));
+                }
+            }
+        }
+        cmds
+    }
+
+    /// Stable hash of the planned commands of all synchronizers, to cheaply detect if the plan changed between runs.
+    /// Relies on the planned commands being ordered deterministically.
+    pub fn plan_hash(&self) -> String {
+        let mut plan = String::new();
+        for sync in &self.synchronizers {
+            plan.push_str(&format!("Synchronizer: {}\n", sync.name));
+            plan.push_str(&sync.plan_text());
+        }
+        format!("{:016x}", fnv1a_hash(plan.as_bytes()))
+    }
+
+    /// The plan as JSON object:
+    ///
+    /// ```json
+    /// {
+    ///   "version": 1, "run_id": "...", "plan_hash": "...",
+    ///   "synchronizers": [{
+    ///     "name": "...", "type": "pacman",
+    ///     "phases": { "pre": [["sudo", "pacman", "-Syu"]], "up": [], "down": [], "post": [] },
+    ///     "diff": { "to_install": [], "to_install_files": [], "to_remove": [], "to_mark_explicit": [], "to_mark_dependency": [] }
+    ///   }],
+    ///   "errors": []
+    /// }
+    /// ```
+    ///
+    /// Phases that were not planned are missing. `diff` is null if it was not computed.
+    pub fn to_json(&self) -> String {
+        let mut synchronizers = Vec::new();
+        for sync in &self.synchronizers {
+            let phases: Vec<String> = sync
+                .phases
+                .iter()
+                .map(|(phase, cmds)| {
+                    let cmds: Vec<String> = cmds.iter().map(|c| json_string_array(c)).collect();
+                    format!("{}: [{}]", json_string(&phase.to_lowercase()), cmds.join(", "))
+                })
+                .collect();
+            let diff = match &sync.diff {
+                Some(d) => format!(
+                    "{{\"to_install\": {}, \"to_install_files\": {}, \"to_remove\": {}, \
+                     \"to_mark_explicit\": {}, \"to_mark_dependency\": {}}}",
+                    json_string_array(&d.to_install),
+                    json_string_array(&d.to_install_files),
+                    json_string_array(&d.to_remove),
+                    json_string_array(&d.to_mark_explicit),
+                    json_string_array(&d.to_mark_dependency)
+                ),
+                None => "null".to_string(),
+            };
+            synchronizers.push(format!(
+                "    {{\"name\": {}, \"type\": {}, \"phases\": {{{}}}, \"diff\": {}}}",
+                json_string(&sync.name),
+                json_string(&sync.sync_type),
+                phases.join(", "),
+                diff
+            ));
+        }
+
+        format!(
+            "{{\n  \"version\": {},\n  \"run_id\": {},\n  \"plan_hash\": {},\n  \"synchronizers\": [\n{}\n  ],\n  \"errors\": {}\n}}",
+            JSON_VERSION,
+            json_string(&self.run_id),
+            json_string(&self.plan_hash()),
+            synchronizers.join(",\n"),
+            json_string_array(&self.errors)
+        )
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = Vec::new();
+        lines.push("System Config Synchronizer Report".to_string());
+        lines.push(format!("Run ID: {}", self.run_id));
+        lines.push(format!("Plan hash: {}", self.plan_hash()));
+        lines.push(format!(
+            "Result: {}",
+            if self.succeeded() { "success" } else { "failure" }
+        ));
+
+        for sync in &self.synchronizers {
+            lines.push(format!("\nSynchronizer: {}", sync.name));
+            if let Some(c) = sync.up_changes {
+                lines.push(format!("  Up changes: {}", c));
+            }
+            if let Some(c) = sync.down_changes {
+                lines.push(format!("  Down changes: {}", c));
+            }
+            for (phase, cmds) in &sync.phases {
+                lines.push(format!("  {} Commands: {}", phase, cmds.len()));
+                for c in cmds {
+                    lines.push(format!("    > {}", c.join(" ")));
+                }
+            }
+        }
+
+        if !self.executed.is_empty() {
+            lines.push("\nExecuted:".to_string());
+            for e in &self.executed {
+                let outcome = match &e.outcome {
+                    CommandOutcome::Succeeded => "ok".to_string(),
+                    CommandOutcome::Skipped => "skipped".to_string(),
+                    CommandOutcome::Failed(err) => format!("failed: {}", err),
+                };
+                lines.push(format!("  {}> {} [{}]", e.phase, e.cmd.join(" "), outcome));
+            }
+        }
+
+        if !self.skipped.is_empty() {
+            lines.push("\nSkipped:".to_string());
+            for k in &self.skipped {
+                lines.push(format!("  {}", k));
+            }
+        }
+
+        if !self.errors.is_empty() {
+            lines.push("\nErrors:".to_string());
+            for e in &self.errors {
+                lines.push(format!("  {}", e));
+            }
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Writes the plan of each synchronizer into its own file in the given directory.
+    /// Files are named by the synchronizer name. If a name appears more than once, an index is appended.
+    /// Returns the paths of the written files.
+    pub fn write_plan_files(&self, dir: &str) -> AResult<Vec<PathBuf>> {
+        fs::create_dir_all(dir)?;
+
+        let mut used_names: Vec<String> = Vec::new();
+        let mut paths = Vec::new();
+        for sync in &self.synchronizers {
+            let base_name = sanitize_file_name(&sync.name);
+            let mut name = base_name.clone();
+            let mut index = 1;
+            while used_names.contains(&name) {
+                index += 1;
+                name = format!("{}-{}", base_name, index);
+            }
+
+            let path = Path::new(dir).join(format!("{}.txt", name));
+            fs::write(&path, sync.plan_text())?;
+            used_names.push(name);
+            paths.push(path);
+        }
+
+        Ok(paths)
+    }
+
+    /// Appends the plan to the journal file. Entries of dry runs are marked as `DRY`,
+    /// so that they are never mistaken for changes that were actually made.
+    pub fn append_to_journal(&self, path: &str, dry: bool) -> AResult<()> {
+        let mut entry = vec![format!(
+            "=== {} {} (plan hash {})",
+            self.run_id,
+            if dry { "DRY" } else { "APPLIED" },
+            self.plan_hash()
+        )];
+        for (phase, cmd) in self.cmds_in_run_order() {
+            entry.push(format!("{}> {}", phase, cmd.join(" ")));
+        }
+        for e in &self.errors {
+            entry.push(format!("Error: {}", e));
+        }
+        entry.push(String::new());
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Could not open journal {}: {}", path, e))?;
+        file.write_all(entry.join("\n").as_bytes())?;
+        Ok(())
+    }
+
+    /// Bash script that runs all planned commands in order and stops at the first failing one.
+    /// Each argument is quoted on its own, so that it reaches the command unchanged.
+    pub fn to_shell_script(&self) -> String {
+        let mut lines = vec![
+            "#!/bin/bash".to_string(),
+            format!("# Plan of run {} (plan hash {})", self.run_id, self.plan_hash()),
+            "set -e".to_string(),
+        ];
+        let mut last_phase = "";
+        for (phase, cmd) in self.cmds_in_run_order() {
+            if phase != last_phase {
+                lines.push(format!("\n# {}", phase));
+                last_phase = phase;
+            }
+            lines.push(cmd.iter().map(|c| shell_quote(c)).collect::<Vec<_>>().join(" "));
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Writes the shell script of the plan and makes it executable.
+    pub fn write_shell_script(&self, path: &str) -> AResult<()> {
+        fs::write(path, self.to_shell_script())?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    pub fn write_to(&self, path: &str) -> AResult<()> {
+        fs::write(path, self.to_text())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cmd;
+
+    fn report(phases: Vec<(&str, Vec<CommandVector>)>) -> RunReport {
+        RunReport {
+            run_id: "test".to_string(),
+            synchronizers: vec![SynchronizerReport {
+                name: "pacman".to_string(),
+                phases: phases.into_iter().map(|(p, c)| (p.to_string(), c)).collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// The plan hash must stay stable across builds.
+    #[test]
+    fn stable_plan_hash() {
+        let report = report(vec![
+            ("Pre", vec![cmd(&["sudo", "pacman", "-Syu"])]),
+            ("Up", vec![cmd(&["sudo", "pacman", "-S", "c", "d"])]),
+        ]);
+        assert_eq!(report.plan_hash(), "7ddf093c9eed0954");
+    }
+
+    #[test]
+    fn shell_script_quoting() {
+        let report = report(vec![
+            ("Pre", vec![cmd(&["sudo", "pacman", "-Syu"])]),
+            (
+                "Up",
+                vec![cmd(&["/opt/my sudo", "pacman", "-S", "it's", "$HOME", "a*", ""])],
+            ),
+        ]);
+        let script = report.to_shell_script();
+        assert_eq!(
+            script.lines().filter(|l| !l.starts_with('#')).collect::<Vec<_>>(),
+            [
+                "set -e",
+                "",
+                "sudo pacman -Syu",
+                "",
+                r#"'/opt/my sudo' pacman -S 'it'\''s' '$HOME' 'a*' ''"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn executed_commands_in_text() {
+        let mut report = report(vec![(
+            "Up",
+            vec![cmd(&["pipx", "install", "a"]), cmd(&["pipx", "install", "b"])],
+        )]);
+        report.executed = vec![
+            ExecutedCommand {
+                phase: "Up".to_string(),
+                cmd: cmd(&["pipx", "install", "a"]),
+                outcome: CommandOutcome::Succeeded,
+            },
+            ExecutedCommand {
+                phase: "Up".to_string(),
+                cmd: cmd(&["pipx", "install", "b"]),
+                outcome: CommandOutcome::Failed("exit status 1".to_string()),
+            },
+        ];
+        let text = report.to_text();
+        assert!(text.contains("Result: failure"));
+        assert!(text.contains("  Up> pipx install a [ok]\n  Up> pipx install b [failed: exit status 1]"));
+
+        report.executed.pop();
+        assert!(report.to_text().contains("Result: success"));
+    }
+
+    #[test]
+    fn json_error_escaping() {
+        assert_eq!(
+            json_error("Unknown key: \"a\\b\"\n"),
+            r#"{"version": 1, "error": "Unknown key: \"a\\b\"\n"}"#
+        );
+    }
+}
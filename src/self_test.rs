@@ -0,0 +1,164 @@
+use crate::package_synchronizer::*;
+use crate::{AResult, CommandVector};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use toml::{Table, Value};
+
+/// Query runner that answers every query command from a fixed table.
+/// Unknown commands are an error, so that a missing query is noticed.
+#[derive(Debug, Clone, Default)]
+pub struct MockQueryRunner {
+    responses: BTreeMap<String, Vec<String>>,
+    root: bool,
+}
+
+impl MockQueryRunner {
+    #[cfg(test)]
+    pub fn running_as_root(mut self) -> Self {
+        self.root = true;
+        self
+    }
+
+    pub fn respond(mut self, cmd: &str, packages: &[&str]) -> Self {
+        self.responses
+            .insert(cmd.to_string(), packages.iter().map(|p| p.to_string()).collect());
+        self
+    }
+}
+
+impl QueryRunner for MockQueryRunner {
+    fn get_packages(&self, cmd: &[String]) -> AResult<Vec<String>> {
+        match self.responses.get(&cmd.join(" ")) {
+            Some(packages) => Ok(packages.clone()),
+            None => Err(format!("Mock has no response for: {}", cmd.join(" ")).into()),
+        }
+    }
+
+    fn is_root(&self) -> AResult<bool> {
+        Ok(self.root)
+    }
+}
+
+/// Synthetic system that the self test and the unit tests run against.
+///
+/// pacman: a is explicit, b is a dependency, x is explicit and unrequired,
+/// y is explicit and required, z is a dependency,
+/// k is the running kernel and explicit and unrequired. Installing d pulls in libd.
+/// Group g has d and e. The foreign packages aur-a and aur-old are explicit and unrequired.
+/// Some responses are deliberately unsorted, like a locale collated command output would be.
+pub fn mock_runner() -> MockQueryRunner {
+    MockQueryRunner::default()
+        .respond("pacman -Sqg g", &["d", "e"])
+        .respond("pacman -Sqg typo", &[])
+        .respond(
+            "pacman -Sqg tex",
+            &["texlive-core", "texlive-bin", "texi", "texinfo", "other", "keep"],
+        )
+        .respond("pacman -Sqg g typo", &["d", "e"])
+        .respond("pacman -Qnq", &["z", "y", "x", "k", "b", "a"])
+        .respond("pacman -Qnqd", &["b", "z"])
+        .respond("pacman -Qnqe", &["a", "k", "x", "y"])
+        .respond("pacman -Qnqet", &["x", "a", "k"])
+        .respond("pacman -Qnqdt", &["z"])
+        .respond("pacman -Qmq", &["aur-old", "aur-a"])
+        .respond("pacman -Qmqd", &[])
+        .respond("pacman -Qmqe", &["aur-a", "aur-old"])
+        .respond("pacman -Qmqet", &["aur-a", "aur-old"])
+        .respond("pacman -Qqo sudo", &["sudo"])
+        .respond("uname -r", &["1.0.0-mock"])
+        .respond("flatpak list --app --columns=application", &["org.old", "org.a"])
+        .respond("pipx-list", &["black", "httpie"])
+        .respond("pacman -Sp --print-format %n c d", &["libd", "c", "d"])
+        .respond("pacman -Qqo /usr/lib/modules/1.0.0-mock/vmlinuz", &["k"])
+}
+
+/// Pacman synchronizer on the system of `mock_runner`.
+///
+/// Config: packages a, b, c-old and group g (d, e), with e blacklisted and c-old renamed to c.
+pub fn mock_synchronizer() -> AResult<PackageSynchronizer> {
+    let mut config = Table::new();
+    config.insert("type".to_string(), Value::String("pacman".to_string()));
+    config.insert(
+        "packages".to_string(),
+        Value::Array(["a", "b", "c-old"].map(|p| Value::String(p.to_string())).into()),
+    );
+    config.insert("groups".to_string(), Value::Array(vec![Value::String("g".to_string())]));
+    config.insert(
+        "blacklist".to_string(),
+        Value::Array(vec![Value::String("e".to_string())]),
+    );
+    let mut replaces = Table::new();
+    replaces.insert("c-old".to_string(), Value::String("c".to_string()));
+    config.insert("replaces".to_string(), Value::Table(replaces));
+
+    new_pacman_with_runner(&config, Arc::new(mock_runner()))
+}
+
+/// Compares the result of one phase against the expected commands and prints PASS or FAIL.
+fn check_phase(name: &str, actual: AResult<Vec<CommandVector>>, expected: &[&[&str]]) -> bool {
+    let expected: Vec<CommandVector> = expected
+        .iter()
+        .map(|c| c.iter().map(|s| s.to_string()).collect())
+        .collect();
+    match actual {
+        Ok(actual) if actual == expected => {
+            println!("PASS: {}", name);
+            true
+        }
+        Ok(actual) => {
+            println!("FAIL: {}", name);
+            println!("    expected: {:?}", expected);
+            println!("    actual:   {:?}", actual);
+            false
+        }
+        Err(e) => {
+            println!("FAIL: {} ({})", name, e);
+            false
+        }
+    }
+}
+
+/// Runs the diff engine against the synthetic system and checks the planned commands of each phase.
+/// A smoke check for the platform the binary runs on; the unit tests cover the details.
+/// Returns true if all checks passed.
+pub fn run_self_test() -> bool {
+    let synchronizer = match mock_synchronizer() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("FAIL: constructing synchronizer ({})", e);
+            return false;
+        }
+    };
+
+    let results = [
+        check_phase(
+            "pre commands",
+            synchronizer.get_pre_cmds(),
+            &[&["sudo", "pacman", "-Syu"]],
+        ),
+        check_phase(
+            "up commands",
+            synchronizer.get_up_cmds(),
+            &[
+                &["sudo", "pacman", "-D", "--asexplicit", "b"],
+                &["sudo", "pacman", "-S", "--needed", "c", "d"],
+            ],
+        ),
+        check_phase(
+            "down commands",
+            synchronizer.get_down_cmds(),
+            &[
+                &["sudo", "pacman", "-D", "--asdeps", "y"],
+                &["sudo", "pacman", "-Rs", "x"],
+            ],
+        ),
+        check_phase(
+            "post commands",
+            synchronizer.get_post_cmds(),
+            &[&["sudo", "pacman", "-Rs", "z"]],
+        ),
+    ];
+
+    results.iter().all(|r| *r)
+}
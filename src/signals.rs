@@ -0,0 +1,91 @@
+use std::os::raw::c_int;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Hand written bindings, to avoid a dependency for two calls of the C library
+const SIGINT: c_int = 2;
+const SIGQUIT: c_int = 3;
+const SIG_IGN: usize = 1;
+const SIG_ERR: usize = usize::MAX;
+
+extern "C" {
+    fn signal(signum: c_int, handler: usize) -> usize;
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static SKIP_PHASE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(signum: c_int) {
+    match signum {
+        SIGINT => INTERRUPTED.store(true, Ordering::SeqCst),
+        SIGQUIT => SKIP_PHASE.store(true, Ordering::SeqCst),
+        _ => (),
+    }
+}
+
+/// Catches SIGINT and SIGQUIT while a planned command runs. The previous handlers are restored on drop.
+///
+/// SIGINT still reaches the command, which aborts on its own. Afterwards `take_interrupted` is set,
+/// so that no further command is started.
+/// SIGQUIT is ignored by the command (see `ignore_quit_in_child`), so that it completes.
+/// Afterwards `take_skip_phase` is set, so that the rest of the current phase is skipped.
+pub struct SignalGuard {
+    previous_int: usize,
+    previous_quit: usize,
+}
+
+impl SignalGuard {
+    pub fn install() -> Self {
+        let handler = handle_signal as extern "C" fn(c_int) as usize;
+        // SAFETY: The handler only stores to atomics, which is async-signal-safe
+        unsafe {
+            SignalGuard {
+                previous_int: signal(SIGINT, handler),
+                previous_quit: signal(SIGQUIT, handler),
+            }
+        }
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        // SAFETY: Restores the handlers that were installed before
+        unsafe {
+            if self.previous_int != SIG_ERR {
+                signal(SIGINT, self.previous_int);
+            }
+            if self.previous_quit != SIG_ERR {
+                signal(SIGQUIT, self.previous_quit);
+            }
+        }
+    }
+}
+
+/// Lets the command ignore SIGQUIT, which is meant for the synchronizer.
+/// An ignored signal stays ignored across exec, unlike a caught one.
+pub fn ignore_quit_in_child(cmd: &mut Command) {
+    // SAFETY: signal is async-signal-safe, so it can be called between fork and exec
+    unsafe {
+        cmd.pre_exec(|| {
+            signal(SIGQUIT, SIG_IGN);
+            Ok(())
+        });
+    }
+}
+
+/// Forgets signals received before, so that they only affect the commands that follow.
+pub fn reset() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    SKIP_PHASE.store(false, Ordering::SeqCst);
+}
+
+/// Whether SIGINT was received since the last call.
+pub fn take_interrupted() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+/// Whether SIGQUIT was received since the last call.
+pub fn take_skip_phase() -> bool {
+    SKIP_PHASE.swap(false, Ordering::SeqCst)
+}
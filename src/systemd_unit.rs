@@ -0,0 +1,118 @@
+use crate::logging::log_info;
+use crate::AResult;
+
+use std::fs;
+use std::path::Path;
+
+/// Base name of the generated unit files.
+pub const UNIT_NAME: &str = "system-config-synchronizer";
+
+/// Quotes an argument for `ExecStart`, if necessary.
+fn quote_exec_arg(arg: &str) -> String {
+    if arg.chars().any(|c| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.to_string()
+    }
+}
+
+/// Checks that the schedule can be used as `OnCalendar` value.
+/// The actual calendar syntax is left to systemd (see `systemd-analyze calendar`).
+fn validate_schedule(schedule: &str) -> AResult<()> {
+    if schedule.trim().is_empty() {
+        return Err("Schedule is empty".into());
+    }
+    if schedule.contains('\n') {
+        return Err("Schedule must be a single line".into());
+    }
+    Ok(())
+}
+
+/// Generates a systemd service and timer unit that run the synchronizer with the given configs on a schedule.
+/// Returns the content of the service and the timer unit.
+pub fn generate_units(exe: &Path, config_paths: &[String], schedule: &str, user: bool) -> AResult<(String, String)> {
+    validate_schedule(schedule)?;
+
+    // The service runs unattended: apply regardless of dry_mode and do not ask for confirmation
+    let mut exec_start = vec![
+        quote_exec_arg(&exe.to_string_lossy()),
+        "--apply".to_string(),
+        "--yes".to_string(),
+    ];
+    for p in config_paths {
+        // The service does not run in the current directory, so use absolute paths
+        let p = fs::canonicalize(p).map_err(|e| format!("Could not resolve config file {}: {}", p, e))?;
+        exec_start.push("--config".to_string());
+        exec_start.push(quote_exec_arg(&p.to_string_lossy()));
+    }
+
+    let service = format!(
+        "[Unit]\n\
+         Description=System Config Synchronizer\n\
+         {}\n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={}\n",
+        if user {
+            "# User unit: commands that need root must not ask for a password (e.g. via NOPASSWD)\n"
+        } else {
+            "Wants=network-online.target\nAfter=network-online.target\n"
+        },
+        exec_start.join(" ")
+    );
+
+    let timer = format!(
+        "[Unit]\n\
+         Description=Run System Config Synchronizer periodically\n\
+         \n\
+         [Timer]\n\
+         OnCalendar={}\n\
+         Persistent=true\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        schedule.trim()
+    );
+
+    Ok((service, timer))
+}
+
+/// Prints the units or writes them into the given directory.
+pub fn output_units(service: &str, timer: &str, unit_dir: Option<&str>, user: bool) -> AResult<()> {
+    let enable_cmd = format!(
+        "systemctl {}enable --now {}.timer",
+        if user { "--user " } else { "" },
+        UNIT_NAME
+    );
+    match unit_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            let service_path = Path::new(dir).join(format!("{}.service", UNIT_NAME));
+            let timer_path = Path::new(dir).join(format!("{}.timer", UNIT_NAME));
+            fs::write(&service_path, service)?;
+            fs::write(&timer_path, timer)?;
+            log_info!("Written {} and {}", service_path.display(), timer_path.display());
+            log_info!("Enable with: {}", enable_cmd);
+        }
+        None => {
+            println!("# {}.service", UNIT_NAME);
+            println!("{}", service);
+            println!("# {}.timer", UNIT_NAME);
+            println!("{}", timer);
+            println!("# Enable with: {}", enable_cmd);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_applies_unattended() {
+        let (service, timer) = generate_units(Path::new("/usr/bin/scs"), &[], "daily", false).unwrap();
+        assert!(service.contains("ExecStart=/usr/bin/scs --apply --yes\n"));
+        assert!(timer.contains("OnCalendar=daily\n"));
+    }
+}
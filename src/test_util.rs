@@ -0,0 +1,57 @@
+use crate::package_synchronizer::QueryRunner;
+use crate::{AResult, CommandVector};
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use toml::{Table, Value};
+
+pub fn str_array(l: &[&str]) -> Value {
+    Value::Array(l.iter().map(|s| Value::String(s.to_string())).collect())
+}
+
+pub fn cmd(l: &[&str]) -> CommandVector {
+    l.iter().map(|s| s.to_string()).collect()
+}
+
+/// Config section of the given type with the given keys.
+pub fn section(sync_type: &str, entries: &[(&str, Value)]) -> Table {
+    let mut config = Table::new();
+    config.insert("type".to_string(), Value::String(sync_type.to_string()));
+    for (key, value) in entries {
+        config.insert(key.to_string(), value.clone());
+    }
+    config
+}
+
+/// Path in the temporary directory that no other test uses, also if the tests run in parallel.
+pub fn unique_temp_path(name: &str) -> PathBuf {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "system-config-synchronizer-test-{}-{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::SeqCst),
+        name
+    ))
+}
+
+/// Query runner that records how many queries run at the same time.
+/// Every query answers with its last argument.
+#[derive(Debug, Default)]
+pub struct CountingQueryRunner {
+    running: AtomicUsize,
+    pub max_running: AtomicUsize,
+    pub calls: AtomicUsize,
+}
+
+impl QueryRunner for CountingQueryRunner {
+    fn get_packages(&self, cmd: &[String]) -> AResult<Vec<String>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_running.fetch_max(running, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        Ok(cmd.last().into_iter().cloned().collect())
+    }
+}